@@ -0,0 +1,24 @@
+use std::process::Command;
+
+// Commit and its time reported by `/version`, both change only with HEAD
+fn main() {
+    let commit = git(&["rev-parse", "--short", "HEAD"]);
+    let commit_time = git(&["log", "-1", "--format=%cI"]);
+
+    println!("cargo:rustc-env=CONSUM_GIT_COMMIT={commit}");
+    println!("cargo:rustc-env=CONSUM_COMMIT_TIME={commit_time}");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+    println!("cargo:rerun-if-changed=.git/packed-refs");
+}
+
+fn git(args: &[&str]) -> String {
+    Command::new("git")
+        .args(args)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|value| value.trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string())
+}
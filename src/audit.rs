@@ -0,0 +1,129 @@
+use std::fmt::Display;
+
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::model::User;
+
+/// Kinds of records in the audit trail
+pub mod entity {
+    pub const ORDER: &str = "order";
+    pub const ORDER_ITEM: &str = "orderItem";
+    pub const PAYMENT: &str = "payment";
+    pub const REQUEST: &str = "request";
+    pub const CATEGORY: &str = "category";
+    pub const SUPPLIER: &str = "supplier";
+    pub const TOKEN: &str = "token";
+}
+
+/// Method and path of the request, e.g. `PUT /orders/5`
+#[derive(Debug, Clone)]
+pub struct Route(pub String);
+
+/// Who makes the change and through which route, passed to every modifying DB method
+#[derive(Debug, Clone)]
+pub struct Audit {
+    user_id: String,
+    route: String,
+}
+
+/// Row of `ApiAudit` table, written in the transaction of the change
+#[derive(Debug, PartialEq)]
+pub struct AuditRecord<'a> {
+    pub user_id: &'a str,
+    pub route: &'a str,
+    pub entity: &'static str,
+    pub entity_id: String,
+    pub before: Option<String>,
+    pub after: Option<String>,
+}
+
+impl Audit {
+    pub fn new(user: &User, route: Route) -> Self {
+        Audit {
+            user_id: user.id.clone(),
+            route: route.0,
+        }
+    }
+
+    pub fn user_id(&self) -> &str {
+        &self.user_id
+    }
+
+    pub fn created<T: Serialize>(
+        &self,
+        entity: &'static str,
+        id: impl Display,
+        after: &T,
+    ) -> Result<AuditRecord<'_>> {
+        Ok(self.record(entity, id, None, Some(serde_json::to_string(after)?)))
+    }
+
+    pub fn updated<T: Serialize>(
+        &self,
+        entity: &'static str,
+        id: impl Display,
+        before: &T,
+        after: &T,
+    ) -> Result<AuditRecord<'_>> {
+        Ok(self.record(
+            entity,
+            id,
+            Some(serde_json::to_string(before)?),
+            Some(serde_json::to_string(after)?),
+        ))
+    }
+
+    pub fn deleted<T: Serialize>(
+        &self,
+        entity: &'static str,
+        id: impl Display,
+        before: &T,
+    ) -> Result<AuditRecord<'_>> {
+        Ok(self.record(entity, id, Some(serde_json::to_string(before)?), None))
+    }
+
+    fn record(
+        &self,
+        entity: &'static str,
+        id: impl Display,
+        before: Option<String>,
+        after: Option<String>,
+    ) -> AuditRecord<'_> {
+        AuditRecord {
+            user_id: &self.user_id,
+            route: &self.route,
+            entity,
+            entity_id: id.to_string(),
+            before,
+            after,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::EnterpriseAccess;
+
+    #[test]
+    fn records_state_before_and_after() {
+        let user = User {
+            id: "7".to_string(),
+            enterprise: EnterpriseAccess::All,
+        };
+        let audit = Audit::new(&user, Route("PATCH /suppliers/3".to_string()));
+
+        let record = audit
+            .updated(entity::SUPPLIER, 3, &[1, 2], &[1, 3])
+            .unwrap();
+        assert_eq!(record.user_id, "7");
+        assert_eq!(record.route, "PATCH /suppliers/3");
+        assert_eq!(record.entity_id, "3");
+        assert_eq!(record.before.as_deref(), Some("[1,2]"));
+        assert_eq!(record.after.as_deref(), Some("[1,3]"));
+
+        let record = audit.deleted(entity::SUPPLIER, 3, &[1, 3]).unwrap();
+        assert_eq!(record.after, None);
+    }
+}
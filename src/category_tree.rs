@@ -0,0 +1,81 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::model::{Category, CategoryNode};
+
+/// Nests the flat list of categories by `parentId`.
+/// Categories whose parent is missing from the list become roots.
+pub fn build_tree(categories: Vec<Category>) -> Vec<CategoryNode> {
+    let ids: HashSet<i32> = categories.iter().map(|cat| cat.catId).collect();
+
+    let mut roots = Vec::new();
+    let mut children: HashMap<i32, Vec<Category>> = HashMap::new();
+    for cat in categories {
+        match cat.parentId {
+            Some(parent_id) if ids.contains(&parent_id) => {
+                children.entry(parent_id).or_default().push(cat)
+            }
+            _ => roots.push(cat),
+        }
+    }
+
+    roots
+        .into_iter()
+        .map(|cat| attach_children(cat, &mut children))
+        .collect()
+}
+
+fn attach_children(category: Category, children: &mut HashMap<i32, Vec<Category>>) -> CategoryNode {
+    let nested = children
+        .remove(&category.catId)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|child| attach_children(child, children))
+        .collect();
+
+    CategoryNode {
+        category,
+        children: nested,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn category(id: i32, parent_id: Option<i32>) -> Category {
+        Category {
+            catId: id,
+            parentId: parent_id,
+            catName: Some(format!("cat {id}")),
+            catUnitCode: 1,
+            code: id * 10,
+        }
+    }
+
+    #[test]
+    fn nests_children_under_parents() {
+        let tree = build_tree(vec![
+            category(1, None),
+            category(2, Some(1)),
+            category(3, Some(2)),
+            category(4, None),
+            category(5, Some(1)),
+        ]);
+
+        assert_eq!(tree.len(), 2);
+        assert_eq!(tree[0].category.catId, 1);
+        let child_ids: Vec<i32> = tree[0].children.iter().map(|c| c.category.catId).collect();
+        assert_eq!(child_ids, vec![2, 5]);
+        assert_eq!(tree[0].children[0].children[0].category.catId, 3);
+        assert!(tree[1].children.is_empty());
+    }
+
+    #[test]
+    fn orphans_become_roots() {
+        let tree = build_tree(vec![category(2, Some(99)), category(3, Some(2))]);
+
+        assert_eq!(tree.len(), 1);
+        assert_eq!(tree[0].category.catId, 2);
+        assert_eq!(tree[0].children[0].category.catId, 3);
+    }
+}
@@ -0,0 +1,56 @@
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
+
+/// Web API for Consum database
+#[derive(Parser, Debug)]
+#[command(version, about)]
+pub struct Cli {
+    /// TOML configuration file, `CONSUM_*` environment variables override its values
+    #[arg(long, env = "CONSUM_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Start even if the startup safety checks fail, for local development only
+    #[arg(long)]
+    pub insecure_dev: bool,
+
+    /// Print the effective configuration with secrets redacted and exit
+    #[arg(long)]
+    pub print_config: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Manage API tokens
+    #[command(subcommand)]
+    Token(TokenCommand),
+}
+
+#[derive(Subcommand, Debug)]
+pub enum TokenCommand {
+    /// Sign a token with the configured JWT secret and print it to stdout
+    Issue {
+        /// Token subject, the user code
+        #[arg(long)]
+        sub: String,
+
+        /// Lifetime like 90m, 12h, 30d or 2w
+        #[arg(long, default_value = "30d")]
+        ttl: String,
+
+        /// Granted scope like orders:read, can be repeated
+        #[arg(long = "scope")]
+        scopes: Vec<String>,
+
+        /// Granted role (admin, clerk or reporting), can be repeated
+        #[arg(long = "role")]
+        roles: Vec<String>,
+
+        /// Enterprise whose data the token is restricted to
+        #[arg(long)]
+        enterprise: Option<i32>,
+    },
+}
@@ -1,18 +1,97 @@
-use std::str::FromStr;
-use std::sync::LazyLock;
+use std::sync::OnceLock;
 use std::{
-    env,
+    env, fs,
     net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    time::Duration,
 };
 
+use jsonwebtoken::Algorithm;
+use tiberius::Config;
+use tracing_subscriber::EnvFilter;
+
+use crate::errors::InvalidConfiguration;
+
 const DEFAULT_PORT: u16 = 3030;
-static DEFAULT_CONNECTION_STRING: &str = "server=tcp:localhost,1433;TrustServerCertificate=true;User=alexey;Password=dosia;Database=Consum";
-//"server=tcp:localhost,1433;IntegratedSecurity=true;TrustServerCertificate=true;Database=Consum";
+// Placeholder only, startup is refused with it unless in insecure development mode
+pub const DEFAULT_CONNECTION_STRING: &str =
+    "server=tcp:localhost,1433;IntegratedSecurity=true;TrustServerCertificate=true;Database=Consum";
 const DEFAULT_MAX_POOL: u32 = 10;
 const DEFAULT_STDOUT: bool = true;
+const DEFAULT_QUERY_API_KEY: bool = true;
+const DEFAULT_JWT_LEEWAY: u64 = 60;
+const DEFAULT_REVOCATION_REFRESH: u64 = 60;
 const DEFAULT_LOG_NAME: &str = "output.log";
-const DEFAULT_JWT_SECRET: &str = "consum_jwt_secret";
+const DEFAULT_LOG_MAX_FILES: usize = 7;
+const DEFAULT_LOG_FILTER: &str = "warn,api=info,service=info,consum_api=info";
+pub const DEFAULT_JWT_SECRET: &str = "consum_jwt_secret";
+
+const ENV_PREFIX: &str = "CONSUM_";
+const REDACTED: &str = "***";
+
+// Keys of the config file, environment variables are the same keys
+// in upper case with `CONSUM_` prefix
+const KEYS: &[&str] = &[
+    "connection_string",
+    "max_pool",
+    "addr",
+    "stdout",
+    "log_path",
+    "jwt_secret",
+    "tls_proxy",
+    "query_api_key",
+    "jwt_secret_kid",
+    "jwt_public_keys",
+    "jwks_file",
+    "jwt_issuer",
+    "jwt_audience",
+    "jwt_leeway",
+    "revocation_refresh",
+    "log_format",
+    "log_filter",
+    "log_rotation",
+    "log_max_size",
+    "log_max_files",
+    "log_compress",
+    "otel_exporter",
+    "otel_endpoint",
+];
+
+/// Format of log lines, on stdout and in the log file
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/// Period after which the log file is rotated
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum LogRotation {
+    Never,
+    Hourly,
+    #[default]
+    Daily,
+}
+
+/// Where spans of requests and DB methods are exported
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum OtelExporter {
+    #[default]
+    None,
+    Otlp,
+    Stdout,
+}
 
+/// Public key verifying tokens whose header has the `kid`
+#[derive(Debug, Clone, PartialEq)]
+pub struct PublicKeyFile {
+    pub kid: String,
+    pub algorithm: Algorithm,
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Configuration {
     connection_string: String,
     max_pool: u32,
@@ -20,6 +99,55 @@ pub struct Configuration {
     stdout_enabled: bool,
     log_path: Option<String>,
     jwt_secret: String,
+    tls_proxy: bool,
+    insecure_dev: bool,
+    query_api_key: bool,
+    jwt_secret_kid: Option<String>,
+    jwt_public_keys: Vec<PublicKeyFile>,
+    jwks_file: Option<PathBuf>,
+    jwt_issuer: Option<String>,
+    jwt_audience: Option<String>,
+    jwt_leeway: u64,
+    revocation_refresh: u64,
+    log_format: LogFormat,
+    log_filter: String,
+    log_rotation: LogRotation,
+    log_max_size: Option<u64>,
+    log_max_files: usize,
+    log_compress: bool,
+    otel_exporter: OtelExporter,
+    otel_endpoint: Option<String>,
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Configuration {
+            connection_string: DEFAULT_CONNECTION_STRING.to_string(),
+            max_pool: DEFAULT_MAX_POOL,
+            addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), DEFAULT_PORT),
+            stdout_enabled: DEFAULT_STDOUT,
+            log_path: None,
+            jwt_secret: DEFAULT_JWT_SECRET.to_string(),
+            tls_proxy: false,
+            insecure_dev: false,
+            query_api_key: DEFAULT_QUERY_API_KEY,
+            jwt_secret_kid: None,
+            jwt_public_keys: Vec::new(),
+            jwks_file: None,
+            jwt_issuer: None,
+            jwt_audience: None,
+            jwt_leeway: DEFAULT_JWT_LEEWAY,
+            revocation_refresh: DEFAULT_REVOCATION_REFRESH,
+            log_format: LogFormat::default(),
+            log_filter: DEFAULT_LOG_FILTER.to_string(),
+            log_rotation: LogRotation::default(),
+            log_max_size: None,
+            log_max_files: DEFAULT_LOG_MAX_FILES,
+            log_compress: false,
+            otel_exporter: OtelExporter::default(),
+            otel_endpoint: None,
+        }
+    }
 }
 
 impl Configuration {
@@ -43,51 +171,516 @@ impl Configuration {
         self.log_path.as_ref()
     }
 
+    pub fn log_format(&self) -> LogFormat {
+        self.log_format
+    }
+
+    /// Levels per target in `RUST_LOG` syntax, used when `RUST_LOG` is not set
+    pub fn log_filter(&self) -> &str {
+        &self.log_filter
+    }
+
+    pub fn log_rotation(&self) -> LogRotation {
+        self.log_rotation
+    }
+
+    /// Size in bytes after which the log file is rotated
+    pub fn log_max_size(&self) -> Option<u64> {
+        self.log_max_size
+    }
+
+    /// Number of rotated log files kept
+    pub fn log_max_files(&self) -> usize {
+        self.log_max_files
+    }
+
+    pub fn log_compress(&self) -> bool {
+        self.log_compress
+    }
+
+    pub fn otel_exporter(&self) -> OtelExporter {
+        self.otel_exporter
+    }
+
+    /// OTLP/HTTP traces endpoint, the exporter defaults apply when not set
+    pub fn otel_endpoint(&self) -> Option<&str> {
+        self.otel_endpoint.as_deref()
+    }
+
     pub fn jwt_secret(&self) -> &str {
         &self.jwt_secret
     }
-}
-static SERVICE_CONFIG: LazyLock<Configuration> = LazyLock::new(|| Configuration {
-    connection_string: get_env_var_or_default(
-        "CONSUM_CONNECTION_STRING",
-        || DEFAULT_CONNECTION_STRING.to_string(),
-    ),
-    max_pool: get_env_var_or_default("CONSUM_MAX_POOL", || DEFAULT_MAX_POOL),
-    addr: get_env_var_or_default("CONSUM_ADDR", || SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), DEFAULT_PORT)),
-    stdout_enabled: get_env_var_or_default("CONSUM_STDOUT", || DEFAULT_STDOUT),
-    log_path: get_log_path(),
-    jwt_secret: get_env_var_or_default("CONSUM_JWT_SECRET", || DEFAULT_JWT_SECRET.to_string()),
-});
-
-// Helper function to retrieve an environment variable or use a default value.
-fn get_env_var_or_default<T: FromStr, F: FnOnce() -> T>(key: &str, default: F) -> T
-where
-    T::Err: std::fmt::Debug,
-{
-    env::var(key)
-        .ok()
-        .and_then(|value| value.parse::<T>().ok())
-        .unwrap_or_else(default)
-}
 
-// Extracted function for handling log path logic
-fn get_log_path() -> Option<String> {
-    env::var("CONSUM_LOG_PATH").ok().map(|path| {
-        if path.to_uppercase() == "DEFAULT" {
-            env::current_exe()
-                .map(|dir| {
-                    dir.as_path()
-                        .with_file_name(DEFAULT_LOG_NAME)
-                        .to_string_lossy()
-                        .to_string()
-                })
-                .unwrap_or(path)
+    /// TLS is terminated by a reverse proxy in front of the service,
+    /// which is the only way to serve https as warp is built without TLS
+    pub fn tls_proxy(&self) -> bool {
+        self.tls_proxy
+    }
+
+    /// Failed startup safety checks are only logged as warnings
+    pub fn insecure_dev(&self) -> bool {
+        self.insecure_dev
+    }
+
+    /// API key is also accepted from `?api_key=`, where it ends up in proxy logs
+    pub fn query_api_key(&self) -> bool {
+        self.query_api_key
+    }
+
+    /// Key id of `jwt_secret`, written to the header of issued tokens
+    pub fn jwt_secret_kid(&self) -> Option<&str> {
+        self.jwt_secret_kid.as_deref()
+    }
+
+    pub fn jwt_public_keys(&self) -> &[PublicKeyFile] {
+        &self.jwt_public_keys
+    }
+
+    /// Local JWKS file with additional verification keys
+    pub fn jwks_file(&self) -> Option<&Path> {
+        self.jwks_file.as_deref()
+    }
+
+    pub fn jwt_issuer(&self) -> Option<&str> {
+        self.jwt_issuer.as_deref()
+    }
+
+    pub fn jwt_audience(&self) -> Option<&str> {
+        self.jwt_audience.as_deref()
+    }
+
+    /// Allowed clock skew in seconds for `exp` and `nbf` validation
+    pub fn jwt_leeway(&self) -> u64 {
+        self.jwt_leeway
+    }
+
+    /// Interval of reloading revoked tokens from the database
+    pub fn revocation_refresh(&self) -> Duration {
+        Duration::from_secs(self.revocation_refresh)
+    }
+
+    /// Set from `--insecure-dev` flag only, not from the config file or environment
+    pub fn set_insecure_dev(&mut self, insecure_dev: bool) {
+        self.insecure_dev = insecure_dev;
+    }
+
+    /// Defaults, overridden by the config file if given, overridden by `CONSUM_*` environment variables
+    pub fn load(path: Option<&Path>) -> Result<Self, InvalidConfiguration> {
+        let file = match path {
+            Some(path) => match fs::read_to_string(path) {
+                Ok(content) => Some((path.display().to_string(), content)),
+                Err(e) => {
+                    return Err(InvalidConfiguration(vec![format!(
+                        "{}: {e}",
+                        path.display()
+                    )]));
+                }
+            },
+            None => None,
+        };
+
+        Self::from_sources(
+            file.as_ref()
+                .map(|(name, content)| (name.as_str(), content.as_str())),
+            |key| env::var(key).ok(),
+        )
+    }
+
+    // Every invalid value is reported, not only the first one
+    pub(crate) fn from_sources(
+        file: Option<(&str, &str)>,
+        env_var: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, InvalidConfiguration> {
+        let mut config = Configuration::default();
+        let mut errors = Vec::new();
+
+        if let Some((name, content)) = file {
+            match content.parse::<toml::Table>() {
+                Ok(table) => {
+                    for (key, value) in table {
+                        let raw = match value {
+                            toml::Value::String(s) => s,
+                            toml::Value::Integer(i) => i.to_string(),
+                            toml::Value::Boolean(b) => b.to_string(),
+                            other => {
+                                errors.push(format!(
+                                    "{name}: `{key}` has unsupported type {}",
+                                    other.type_str()
+                                ));
+                                continue;
+                            }
+                        };
+                        if let Err(e) = config.set(&key, &raw) {
+                            errors.push(format!("{name}: `{key}` {e}"));
+                        }
+                    }
+                }
+                Err(e) => errors.push(format!("{name}: {e}")),
+            }
+        }
+
+        for key in KEYS {
+            let var = format!("{ENV_PREFIX}{}", key.to_uppercase());
+            if let Some(raw) = env_var(&var)
+                && let Err(e) = config.set(key, &raw)
+            {
+                errors.push(format!("{var}: {e}"));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(config)
         } else {
-            path
+            Err(InvalidConfiguration(errors))
+        }
+    }
+
+    fn set(&mut self, key: &str, raw: &str) -> Result<(), String> {
+        match key {
+            "connection_string" => {
+                Config::from_ado_string(raw).map_err(|e| format!("is invalid: {e}"))?;
+                self.connection_string = raw.to_string();
+            }
+            "max_pool" => {
+                self.max_pool = match raw.parse() {
+                    Ok(0) | Err(_) => {
+                        return Err(format!("must be a positive number, got `{raw}`"));
+                    }
+                    Ok(value) => value,
+                }
+            }
+            "addr" => {
+                self.addr = raw
+                    .parse()
+                    .map_err(|_| format!("must be an `ip:port` address, got `{raw}`"))?
+            }
+            "stdout" => self.stdout_enabled = parse_bool(raw)?,
+            "log_path" => self.log_path = Some(log_path(raw)),
+            "jwt_secret" => {
+                if raw.is_empty() {
+                    return Err("must not be empty".to_string());
+                }
+                self.jwt_secret = raw.to_string();
+            }
+            "tls_proxy" => self.tls_proxy = parse_bool(raw)?,
+            // Only the command line flag turns the safety checks off, see `set_insecure_dev`
+            "insecure_dev" => return Err("can only be set with --insecure-dev flag".to_string()),
+            "query_api_key" => self.query_api_key = parse_bool(raw)?,
+            "jwt_secret_kid" => self.jwt_secret_kid = non_empty(raw),
+            "jwt_public_keys" => self.jwt_public_keys = parse_public_keys(raw)?,
+            "jwks_file" => self.jwks_file = non_empty(raw).map(PathBuf::from),
+            "jwt_issuer" => self.jwt_issuer = non_empty(raw),
+            "jwt_audience" => self.jwt_audience = non_empty(raw),
+            "jwt_leeway" => {
+                self.jwt_leeway = raw
+                    .parse()
+                    .map_err(|_| format!("must be a number of seconds, got `{raw}`"))?
+            }
+            "log_format" => {
+                self.log_format = match raw.to_lowercase().as_str() {
+                    "text" => LogFormat::Text,
+                    "json" => LogFormat::Json,
+                    _ => return Err(format!("must be `text` or `json`, got `{raw}`")),
+                }
+            }
+            "log_filter" => {
+                EnvFilter::builder()
+                    .parse(raw)
+                    .map_err(|e| format!("is not a valid filter: {e}"))?;
+                self.log_filter = raw.to_string();
+            }
+            "log_rotation" => {
+                self.log_rotation = match raw.to_lowercase().as_str() {
+                    "never" => LogRotation::Never,
+                    "hourly" => LogRotation::Hourly,
+                    "daily" => LogRotation::Daily,
+                    _ => {
+                        return Err(format!("must be `never`, `hourly` or `daily`, got `{raw}`"));
+                    }
+                }
+            }
+            // Megabytes, 0 turns the limit off
+            "log_max_size" => {
+                let megabytes: u64 = raw
+                    .parse()
+                    .map_err(|_| format!("must be a number of megabytes, got `{raw}`"))?;
+                self.log_max_size = match megabytes {
+                    0 => None,
+                    _ => Some(
+                        megabytes
+                            .checked_mul(1024 * 1024)
+                            .ok_or_else(|| format!("is too large, got `{raw}` megabytes"))?,
+                    ),
+                }
+            }
+            "log_max_files" => {
+                self.log_max_files = raw
+                    .parse()
+                    .map_err(|_| format!("must be a number of files, got `{raw}`"))?
+            }
+            "log_compress" => self.log_compress = parse_bool(raw)?,
+            "otel_exporter" => {
+                self.otel_exporter = match raw.to_lowercase().as_str() {
+                    "none" => OtelExporter::None,
+                    "otlp" => OtelExporter::Otlp,
+                    "stdout" => OtelExporter::Stdout,
+                    _ => return Err(format!("must be `none`, `otlp` or `stdout`, got `{raw}`")),
+                }
+            }
+            "otel_endpoint" => self.otel_endpoint = non_empty(raw),
+            "revocation_refresh" => {
+                self.revocation_refresh = match raw.parse() {
+                    Ok(0) | Err(_) => {
+                        return Err(format!("must be a positive number of seconds, got `{raw}`"));
+                    }
+                    Ok(value) => value,
+                }
+            }
+            _ => return Err(format!("is not a known key, expected one of {KEYS:?}")),
+        }
+        Ok(())
+    }
+
+    /// Effective configuration in config file format, with secrets redacted
+    pub fn redacted(&self) -> String {
+        let mut table = toml::Table::new();
+        table.insert(
+            "connection_string".into(),
+            redact_connection_string(&self.connection_string).into(),
+        );
+        table.insert("max_pool".into(), i64::from(self.max_pool).into());
+        table.insert("addr".into(), self.addr.to_string().into());
+        table.insert("stdout".into(), self.stdout_enabled.into());
+        let log_format = match self.log_format {
+            LogFormat::Text => "text",
+            LogFormat::Json => "json",
+        };
+        table.insert("log_format".into(), log_format.into());
+        table.insert("log_filter".into(), self.log_filter.clone().into());
+        if let Some(path) = &self.log_path {
+            table.insert("log_path".into(), path.clone().into());
         }
-    })
+        let log_rotation = match self.log_rotation {
+            LogRotation::Never => "never",
+            LogRotation::Hourly => "hourly",
+            LogRotation::Daily => "daily",
+        };
+        table.insert("log_rotation".into(), log_rotation.into());
+        let log_max_size = self.log_max_size.map_or(0, |size| size / 1024 / 1024);
+        table.insert("log_max_size".into(), (log_max_size as i64).into());
+        table.insert("log_max_files".into(), (self.log_max_files as i64).into());
+        table.insert("log_compress".into(), self.log_compress.into());
+        let otel_exporter = match self.otel_exporter {
+            OtelExporter::None => "none",
+            OtelExporter::Otlp => "otlp",
+            OtelExporter::Stdout => "stdout",
+        };
+        table.insert("otel_exporter".into(), otel_exporter.into());
+        table.insert("jwt_secret".into(), REDACTED.into());
+        table.insert("tls_proxy".into(), self.tls_proxy.into());
+        table.insert("query_api_key".into(), self.query_api_key.into());
+        let optional = [
+            ("jwt_secret_kid", self.jwt_secret_kid.clone()),
+            (
+                "jwks_file",
+                self.jwks_file
+                    .as_ref()
+                    .map(|path| path.display().to_string()),
+            ),
+            ("jwt_issuer", self.jwt_issuer.clone()),
+            ("jwt_audience", self.jwt_audience.clone()),
+            ("otel_endpoint", self.otel_endpoint.clone()),
+        ];
+        for (key, value) in optional {
+            if let Some(value) = value {
+                table.insert(key.into(), value.into());
+            }
+        }
+        if !self.jwt_public_keys.is_empty() {
+            let keys: Vec<String> = self
+                .jwt_public_keys
+                .iter()
+                .map(|key| format!("{}={:?}:{}", key.kid, key.algorithm, key.path.display()))
+                .collect();
+            table.insert("jwt_public_keys".into(), keys.join(",").into());
+        }
+        table.insert("jwt_leeway".into(), (self.jwt_leeway as i64).into());
+        table.insert(
+            "revocation_refresh".into(),
+            (self.revocation_refresh as i64).into(),
+        );
+        table.to_string()
+    }
+}
+
+fn parse_bool(raw: &str) -> Result<bool, String> {
+    raw.parse()
+        .map_err(|_| format!("must be `true` or `false`, got `{raw}`"))
+}
+
+fn non_empty(raw: &str) -> Option<String> {
+    Some(raw.trim().to_string()).filter(|value| !value.is_empty())
+}
+
+// Comma separated `kid=ALG:path` entries, e.g. `k1=RS256:/etc/consum/k1.pem`
+fn parse_public_keys(raw: &str) -> Result<Vec<PublicKeyFile>, String> {
+    raw.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let invalid = || format!("entry `{entry}` must look like `kid=RS256:path.pem`");
+            let (kid, key) = entry.split_once('=').ok_or_else(invalid)?;
+            let (algorithm, path) = key.split_once(':').ok_or_else(invalid)?;
+            let algorithm = match algorithm.parse() {
+                Ok(alg @ (Algorithm::RS256 | Algorithm::ES256 | Algorithm::EdDSA)) => alg,
+                _ => {
+                    return Err(format!(
+                        "entry `{entry}` has unsupported algorithm, expected RS256, ES256 or EdDSA"
+                    ));
+                }
+            };
+            Ok(PublicKeyFile {
+                kid: kid.trim().to_string(),
+                algorithm,
+                path: PathBuf::from(path.trim()),
+            })
+        })
+        .collect()
+}
+
+// `default` means the default file name next to the executable
+fn log_path(path: &str) -> String {
+    if path.to_uppercase() == "DEFAULT" {
+        env::current_exe()
+            .map(|dir| {
+                dir.as_path()
+                    .with_file_name(DEFAULT_LOG_NAME)
+                    .to_string_lossy()
+                    .to_string()
+            })
+            .unwrap_or(path.to_string())
+    } else {
+        path.to_string()
+    }
+}
+
+fn redact_connection_string(connection_string: &str) -> String {
+    connection_string
+        .split(';')
+        .map(|part| match part.split_once('=') {
+            Some((key, _)) if matches!(key.trim().to_lowercase().as_str(), "password" | "pwd") => {
+                format!("{key}={REDACTED}")
+            }
+            _ => part.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(";")
+}
+
+static SERVICE_CONFIG: OnceLock<Configuration> = OnceLock::new();
+
+/// Makes the loaded configuration available through `get`, must be called once at startup
+pub fn init(config: Configuration) -> &'static Configuration {
+    SERVICE_CONFIG.get_or_init(|| config)
 }
 
 pub fn get() -> &'static Configuration {
-    &SERVICE_CONFIG
+    SERVICE_CONFIG
+        .get()
+        .expect("configuration is not initialized")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn no_env(_: &str) -> Option<String> {
+        None
+    }
+
+    #[test]
+    fn env_overrides_file() {
+        let file = "max_pool = 4\naddr = \"0.0.0.0:8080\"\nstdout = false";
+        let config = Configuration::from_sources(Some(("consum.toml", file)), |key| {
+            (key == "CONSUM_MAX_POOL").then(|| "20".to_string())
+        })
+        .unwrap();
+
+        assert_eq!(config.max_pool(), 20);
+        assert_eq!(config.addr().to_string(), "0.0.0.0:8080");
+        assert!(!config.stdout_enabled());
+        assert_eq!(config.jwt_secret(), DEFAULT_JWT_SECRET);
+    }
+
+    #[test]
+    fn reports_every_invalid_key() {
+        let file = "max_pool = 0\nport = 1\nstdout = \"yes\"";
+        let err = Configuration::from_sources(Some(("consum.toml", file)), |key| {
+            (key == "CONSUM_ADDR").then(|| "localhost".to_string())
+        })
+        .unwrap_err();
+
+        assert_eq!(err.0.len(), 4, "{err}");
+        assert!(err.0.iter().any(|e| e.starts_with("consum.toml: `port`")));
+        assert!(err.0.iter().any(|e| e.starts_with("CONSUM_ADDR:")));
+    }
+
+    #[test]
+    fn insecure_dev_is_not_configurable() {
+        let config = Configuration::from_sources(None, |key| {
+            (key == "CONSUM_INSECURE_DEV").then(|| "true".to_string())
+        })
+        .unwrap();
+        assert!(!config.insecure_dev());
+
+        let file = "insecure_dev = true";
+        assert!(Configuration::from_sources(Some(("consum.toml", file)), no_env).is_err());
+    }
+
+    #[test]
+    fn parses_public_keys() {
+        let file = "jwt_public_keys = \"k1=RS256:/keys/k1.pem, k2=EdDSA:/keys/k2.pem\"";
+        let config = Configuration::from_sources(Some(("consum.toml", file)), no_env).unwrap();
+
+        let keys = config.jwt_public_keys();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[1].kid, "k2");
+        assert_eq!(keys[1].algorithm, Algorithm::EdDSA);
+
+        let file = "jwt_public_keys = \"k1=HS256:/keys/k1.pem,k2\"";
+        assert!(Configuration::from_sources(Some(("consum.toml", file)), no_env).is_err());
+    }
+
+    #[test]
+    fn parses_log_settings() {
+        let file = "log_rotation = \"hourly\"\nlog_max_size = 5\nlog_compress = true\n\
+            log_filter = \"info,hyper::proto=warn\"";
+        let config = Configuration::from_sources(Some(("consum.toml", file)), no_env).unwrap();
+
+        assert_eq!(config.log_rotation(), LogRotation::Hourly);
+        assert_eq!(config.log_max_size(), Some(5 * 1024 * 1024));
+        assert_eq!(config.log_max_files(), DEFAULT_LOG_MAX_FILES);
+        assert!(config.log_compress());
+        assert_eq!(config.log_filter(), "info,hyper::proto=warn");
+
+        let file = "log_filter = \"hyper=loud\"";
+        assert!(Configuration::from_sources(Some(("consum.toml", file)), no_env).is_err());
+        let file = "log_max_size = 9223372036854775807";
+        let err = Configuration::from_sources(Some(("consum.toml", file)), no_env).unwrap_err();
+        assert!(err.to_string().contains("too large"), "{err}");
+    }
+
+    #[test]
+    fn redacts_secrets() {
+        let file = "connection_string = \"Server=db;User=sa;Password=hunter2;Database=Consum\"\n\
+            jwt_secret = \"s3cr3t-value\"";
+        let printed = Configuration::from_sources(Some(("consum.toml", file)), no_env)
+            .unwrap()
+            .redacted();
+
+        assert!(!printed.contains("hunter2"), "{printed}");
+        assert!(!printed.contains("s3cr3t"), "{printed}");
+        assert!(printed.contains("Password=***"));
+        assert!(printed.contains("jwt_secret = \"***\""));
+    }
 }
@@ -37,6 +37,9 @@ use tokio::net::TcpStream;
 use tokio_util::compat::Compat;
 use tokio_util::compat::TokioAsyncWriteCompatExt;
 
+// Leaves the session as a new connection would be
+const RESET_SESSION: &str = "IF @@TRANCOUNT > 0 ROLLBACK TRANSACTION; SET XACT_ABORT OFF";
+
 #[derive(Clone, Debug)]
 pub struct TiberiusConnection {
     config: Config,
@@ -63,9 +66,11 @@ impl bb8::ManageConnection for TiberiusConnection {
         Client::connect(config, tcp.compat_write()).await
     }
 
+    /// Also run on every checkout, where it rolls back a transaction left open
+    /// by a request dropped between `BEGIN` and `COMMIT`, and so releases its locks.
     async fn is_valid(&self, conn: &mut Self::Connection) -> Result<(), Self::Error> {
         //debug!("Checking {:?}", conn);
-        conn.simple_query("").await?.into_row().await?;
+        conn.simple_query(RESET_SESSION).await?.into_row().await?;
         Ok(())
     }
 
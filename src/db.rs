@@ -1,15 +1,68 @@
+use std::time::Instant;
+
 use anyhow::{Context, Result, bail};
-use tiberius::{FromSql, Row};
+use tiberius::{FromSql, Query, Row};
 
 use crate::{
     DBPool,
-    errors::{DBRecordNotFound, MissingRequiredField},
+    audit::{Audit, AuditRecord, entity},
+    auth::EnterpriseAccess,
+    category_tree,
+    connection_manager::TiberiusConnection,
+    errors::{
+        CategoryCycle, DBRecordNotFound, IllegalRequestTransition, InvalidPaging,
+        MissingRequiredField, RecordInUse, UnknownCategoryCode, UnknownParentCategory,
+    },
+    etag::IfMatch,
+    metrics,
     model::{
-        Category, CreateCategory, CreateOrder, CreateSupplier, Order, OrderView, Supplier,
-        ViewFilter,
+        AuditEntry, AuditFilter, Category, CategoryNode, ConsRequest, CreateCategory, CreateOrder,
+        CreateOrderItem, CreatePayment, CreateRequest, CreateSupplier, CreateToken, CreatedPayment,
+        Order, OrderItem, OrderView, PatchOrder, Payment, PaymentView, RequestFilter, Supplier,
+        SupplierFilter, UpdateCategory, UpdateOrder, UpdateSupplier, ViewFilter,
     },
+    pagination::{Page, PageRequest},
+    query_builder::{QueryBuilder, contains_pattern, order_by_clause},
 };
 
+type Connection = <TiberiusConnection as bb8::ManageConnection>::Connection;
+
+// Categories below @P1, with their distance from it
+const CATEGORY_DESCENDANTS_SQL: &str = "with tree as ( \
+    select CatID, ParentID, CatName, CatUnitCode, Code, 1 as Depth from ConsCats where ParentID = @P1 \
+    union all \
+    select c.CatID, c.ParentID, c.CatName, c.CatUnitCode, c.Code, t.Depth + 1 \
+    from ConsCats c inner join tree t on c.ParentID = t.CatID)";
+
+// Categories above @P1, with their distance from it
+const CATEGORY_ANCESTORS_SQL: &str = "with chain as ( \
+    select CatID, ParentID, CatName, CatUnitCode, Code, 0 as Depth from ConsCats where CatID = @P1 \
+    union all \
+    select c.CatID, c.ParentID, c.CatName, c.CatUnitCode, c.Code, ch.Depth + 1 \
+    from ConsCats c inner join chain ch on c.CatID = ch.ParentID)";
+
+const ORDER_VIEW_SQL: &str = "select ConsID, EnterpriseID, IncomeDate, AccountNum, AccountDate, \
+    ISNULL((select sum(AccountGrn) from ConsOrderItem coi where coi.ConsID = cr.ConsID), 0) as AccountGrn, \
+    cr.SellerID, BySelf, HasTrust, TrustSer, TrustNum, \
+    ISNULL((select sum(PaidGrn) from ConsPayment cp where cp.ConsID = cr.ConsID), 0) as PaidGrn, cr.Comment \
+    from ConsOrders cr left join Seller s on cr.SellerID = s.SellerID";
+
+// Order view fields which can be used for sorting, mapped to the view columns
+const ORDER_VIEW_SORT_FIELDS: &[(&str, &str)] = &[
+    ("consId", "ConsID"),
+    ("incomeDate", "IncomeDate"),
+    ("supplierId", "SellerID"),
+    ("accountNum", "AccountNum"),
+    ("accountDate", "AccountDate"),
+    ("bySelf", "BySelf"),
+    ("hasTrust", "HasTrust"),
+    ("trustSer", "TrustSer"),
+    ("trustNum", "TrustNum"),
+    ("enterpriseId", "EnterpriseID"),
+    ("paidGrn", "PaidGrn"),
+    ("accountGrn", "AccountGrn"),
+];
+
 trait RowExt {
     // fn get_string(&self, col: &str) -> Option<String>;
     // fn get_value<'a, T>(&'a self, col: &str) -> T where T: Default + FromSql<'a>;
@@ -91,109 +144,1116 @@ impl DB {
         DB { db_pool }
     }
 
-    pub async fn get_orders(&self) -> Result<Vec<Order>> {
-        let mut client = self.db_pool.get().await?;
-        let stream = client
-            .simple_query("SELECT top (100) * from ConsOrders")
+    pub fn pool_state(&self) -> bb8::State {
+        self.db_pool.state()
+    }
+
+    async fn connection(&self) -> Result<bb8::PooledConnection<'_, TiberiusConnection>> {
+        let start = Instant::now();
+        let connection = self.db_pool.get().await?;
+        metrics::observe_acquire(start);
+        Ok(connection)
+    }
+
+    pub async fn get_orders(
+        &self,
+        page: PageRequest,
+        access: EnterpriseAccess,
+    ) -> Result<Page<Order>> {
+        metrics::sql("get_orders", async {
+            let mut builder = QueryBuilder::new();
+            access.restrict(&mut builder, "EnterpriseID")?;
+
+            let mut client = self.connection().await?;
+            let total = Self::count(
+                &mut client,
+                builder.build(format!(
+                    "select count(*) as Total from ConsOrders{}",
+                    builder.where_clause()
+                )),
+            )
             .await?;
-        let rows: Vec<Row> = stream.into_first_result().await?;
 
-        let orders: Result<Vec<_>> = rows.iter().map(Self::try_map_order).collect();
+            page.keyset(&mut builder, "ConsID");
+            let query_sql = format!(
+                "SELECT * from ConsOrders{} order by ConsID{}",
+                builder.where_clause(),
+                page.fetch_clause(&mut builder)
+            );
+            let stream = builder.build(query_sql).query(&mut client).await?;
+            let rows: Vec<Row> = stream.into_first_result().await?;
+            metrics::record_rows(rows.len());
+
+            let list: Vec<_> = rows
+                .iter()
+                .map(Self::try_map_order)
+                .collect::<Result<_>>()?;
+            info!("Orders count = {}", list.len());
+
+            Ok(Page::new(list, total, page, |order| order.consId))
+        })
+        .await
+    }
+
+    pub async fn get_orders_filtered(
+        &self,
+        filter: ViewFilter,
+        page: PageRequest,
+        access: EnterpriseAccess,
+    ) -> Result<Page<OrderView>> {
+        metrics::sql("get_orders_filtered", async {
+            let mut client = self.connection().await?;
+
+            if page.after.is_some() && !filter.orderBy.is_empty() {
+                bail!(InvalidPaging(
+                    "after can only be used with the default ordering".to_string()
+                ))
+            }
+            let order_by = order_by_clause(&filter.orderBy, ORDER_VIEW_SORT_FIELDS)?;
+            // consId keeps the order stable between pages
+            let order_by = if order_by.is_empty() {
+                " order by ConsID".to_string()
+            } else {
+                order_by + ", ConsID"
+            };
+
+            let mut builder = QueryBuilder::new();
+            builder.optional("IncomeDate", ">=", filter.incomeDateFrom);
+            builder.optional("IncomeDate", "<=", filter.incomeDateTo);
+            builder.optional("AccountDate", ">=", filter.accountDateFrom);
+            builder.optional("AccountDate", "<=", filter.accountDateTo);
+            builder.optional("SellerID", "=", filter.supplierId);
+            builder.optional("EnterpriseID", "=", filter.enterpriseId);
+            access.restrict(&mut builder, "EnterpriseID")?;
+            builder.optional("HasTrust", "=", filter.hasTrust);
+            if filter.unpaidOnly {
+                builder.raw_condition("PaidGrn < AccountGrn");
+            }
 
-        if let Ok(list) = orders {
+            let total = Self::count(
+                &mut client,
+                builder.build(format!(
+                    "select count(*) as Total from ({ORDER_VIEW_SQL}) v{}",
+                    builder.where_clause()
+                )),
+            )
+            .await?;
+
+            page.keyset(&mut builder, "ConsID");
+            let query_sql = format!(
+                "select * from ({ORDER_VIEW_SQL}) v{}{order_by}{}",
+                builder.where_clause(),
+                page.fetch_clause(&mut builder)
+            );
+            let stream = builder.build(query_sql).query(&mut client).await?;
+            let rows: Vec<Row> = stream.into_first_result().await?;
+            metrics::record_rows(rows.len());
+
+            let list: Vec<_> = rows
+                .iter()
+                .map(Self::try_map_order_view)
+                .collect::<Result<_>>()?;
             info!("Orders count = {}", list.len());
-            return Ok(list);
-        }
 
-        orders
+            Ok(Page::new(list, total, page, |order| order.consId))
+        })
+        .await
     }
 
-    pub async fn get_orders_filtered(&self, filter: ViewFilter) -> Result<Vec<OrderView>> {
-        let mut client = self.db_pool.get().await?;
+    pub async fn get_order(&self, id: i32, access: EnterpriseAccess) -> Result<Order> {
+        metrics::sql("get_order", async {
+            //  Ok(Order{consId: id,
+            //  orderState: 1,
+            //  incomeDate: None,
+            //  supplierId: 8,
+            //  accountNum: Some("20515".to_owned()),
+            //  accountDate: Some(NaiveDateTime::new(NaiveDate::from_ymd(2020, 1, 1), NaiveTime::from_hms(11, 11, 11))),
+            //  bySelf: None,
+            //  hasTrust: false,
+            //  trustSer: None,
+            //  trustNum: None,
+            //  comment: None,
+            //  enterpriseId: 1
+            // })
+
+            let mut client = self.connection().await?;
+            let order = Self::fetch_order(&mut client, id).await?;
+            if !access.can_read(order.enterpriseId)? {
+                bail!(DBRecordNotFound)
+            }
+            Ok(order)
+        })
+        .await
+    }
 
-        let mut query_sql = "select ConsID, EnterpriseID, IncomeDate, AccountNum, AccountDate, \
-           ISNULL((select sum(AccountGrn) from ConsOrderItem coi where coi.ConsID = cr.ConsID), 0) as AccountGrn, \
-           cr.SellerID, BySelf, HasTrust, TrustSer, TrustNum, \
-           ISNULL((select sum(PaidGrn) from ConsPayment cp where cp.ConsID = cr.ConsID), 0) as PaidGrn, cr.Comment \
-           from ConsOrders cr left join Seller s on cr.SellerID = s.SellerID".to_string();
-        if let Some(order) = filter.orderBy {
-            query_sql.push_str(&(" order by ".to_string() + &order));
-        }
-        let stream = client.simple_query(query_sql).await?;
-        let rows: Vec<Row> = stream.into_first_result().await?;
+    pub async fn create_order(
+        &self,
+        create_order: CreateOrder,
+        access: EnterpriseAccess,
+        audit: &Audit,
+    ) -> Result<Order> {
+        metrics::sql("create_order", async {
+            access.check_write(create_order.enterpriseId)?;
+
+            let mut client = self.connection().await?;
+            Self::in_transaction(&mut client, async |client| {
+                let result = client.query(
+                        "declare @rc int; exec @rc = up_NewAccount @P1, @P2, @P3, @P4, @P5, @P6, @P7, @P8, @P9, @P10; select @rc as Id", 
+                        &[&create_order.accountNum,
+                        &create_order.accountDate,
+                        &create_order.incomeDate,
+                        &create_order.hasTrust,
+                        &create_order.trustSer,
+                        &create_order.trustNum,
+                        &create_order.supplierId,
+                        &create_order.bySelf,
+                        &create_order.comment,
+                        &create_order.enterpriseId])
+                    .await?
+                    .into_row()
+                    .await?;
+
+                if let Some(row) = result {
+                    let id_value = row.try_get::<i32, &str>("Id")?;
+                    if let Some(id) = id_value {
+                        let order = Self::fetch_order(client, id).await?;
+                        Self::write_audit(client, audit.created(entity::ORDER, id, &order)?).await?;
+                        return Ok(order);
+                    }
+                }
+
+                bail!(DBRecordNotFound)
+            })
+            .await
+        })
+        .await
+    }
 
-        let orders: Result<Vec<_>> = rows.iter().map(Self::try_map_order_view).collect();
+    pub async fn update_order(
+        &self,
+        id: i32,
+        order: UpdateOrder,
+        if_match: &IfMatch,
+        access: EnterpriseAccess,
+        audit: &Audit,
+    ) -> Result<Order> {
+        metrics::sql("update_order", async {
+            access.check_write(order.enterpriseId)?;
+            let mut client = self.connection().await?;
+
+            Self::in_transaction(&mut client, async |client| {
+                let before = Self::lock_visible_order(client, id, access).await?;
+                if_match.check(&before)?;
+                let result = client.execute(
+                        "update ConsOrders set OrderState = @P2, AccountNum = @P3, AccountDate = @P4, IncomeDate = @P5, \
+                        HasTrust = @P6, TrustSer = @P7, TrustNum = @P8, SellerID = @P9, BySelf = @P10, Comment = @P11, \
+                        EnterpriseID = @P12 \
+                        where ConsID = @P1",
+                        &[&id,
+                        &order.orderState,
+                        &order.accountNum,
+                        &order.accountDate,
+                        &order.incomeDate,
+                        &order.hasTrust,
+                        &order.trustSer,
+                        &order.trustNum,
+                        &order.supplierId,
+                        &order.bySelf,
+                        &order.comment,
+                        &order.enterpriseId])
+                    .await?;
+
+                if let Some(count) = result.rows_affected().first()
+                    && count > &0
+                {
+                    let after = Self::fetch_order(client, id).await?;
+                    Self::write_audit(client, audit.updated(entity::ORDER, id, &before, &after)?)
+                        .await?;
+                    return Ok(after);
+                }
+
+                bail!(DBRecordNotFound)
+            })
+            .await
+        })
+        .await
+    }
 
-        if let Ok(list) = orders {
-            info!("Orders count = {}", list.len());
-            return Ok(list);
-        }
+    pub async fn patch_order(
+        &self,
+        id: i32,
+        order: PatchOrder,
+        if_match: &IfMatch,
+        access: EnterpriseAccess,
+        audit: &Audit,
+    ) -> Result<Order> {
+        metrics::sql("patch_order", async {
+            if let Some(enterprise_id) = order.enterpriseId {
+                access.check_write(enterprise_id)?;
+            }
+            let mut builder = QueryBuilder::new();
+            builder.set_optional("OrderState", order.orderState);
+            builder.set_optional("AccountNum", order.accountNum);
+            builder.set_optional("AccountDate", order.accountDate);
+            builder.set_optional("IncomeDate", order.incomeDate);
+            builder.set_optional("HasTrust", order.hasTrust);
+            builder.set_optional("TrustSer", order.trustSer);
+            builder.set_optional("TrustNum", order.trustNum);
+            builder.set_optional("SellerID", order.supplierId);
+            builder.set_optional("BySelf", order.bySelf);
+            builder.set_optional("Comment", order.comment);
+            builder.set_optional("EnterpriseID", order.enterpriseId);
+
+            let mut client = self.connection().await?;
+            if !builder.has_assignments() {
+                let current = Self::fetch_order(&mut client, id).await?;
+                if !access.can_read(current.enterpriseId)? {
+                    bail!(DBRecordNotFound)
+                }
+                if_match.check(&current)?;
+                return Ok(current);
+            }
 
-        orders
+            builder.condition("ConsID", "=", id);
+            let query_sql = format!(
+                "update ConsOrders{}{}",
+                builder.set_clause(),
+                builder.where_clause()
+            );
+
+            Self::in_transaction(&mut client, async |client| {
+                let before = Self::lock_visible_order(client, id, access).await?;
+                if_match.check(&before)?;
+                let result = builder.build(query_sql).execute(client).await?;
+
+                if let Some(count) = result.rows_affected().first()
+                    && count > &0
+                {
+                    let after = Self::fetch_order(client, id).await?;
+                    Self::write_audit(client, audit.updated(entity::ORDER, id, &before, &after)?)
+                        .await?;
+                    return Ok(after);
+                }
+
+                bail!(DBRecordNotFound)
+            })
+            .await
+        })
+        .await
     }
 
-    pub async fn get_order(&self, id: i32) -> Result<Order> {
-        //  Ok(Order{consId: id,
-        //  orderState: 1,
-        //  incomeDate: None,
-        //  supplierId: 8,
-        //  accountNum: Some("20515".to_owned()),
-        //  accountDate: Some(NaiveDateTime::new(NaiveDate::from_ymd(2020, 1, 1), NaiveTime::from_hms(11, 11, 11))),
-        //  bySelf: None,
-        //  hasTrust: false,
-        //  trustSer: None,
-        //  trustNum: None,
-        //  comment: None,
-        //  enterpriseId: 1
-        // })
+    // Items and payments of the order are removed only when `cascade` is requested
+    pub async fn delete_order(
+        &self,
+        id: i32,
+        cascade: bool,
+        if_match: &IfMatch,
+        access: EnterpriseAccess,
+        audit: &Audit,
+    ) -> Result<()> {
+        metrics::sql("delete_order", async {
+            let mut client = self.connection().await?;
+
+            Self::in_transaction(&mut client, async |client| {
+                let before = Self::lock_visible_order(client, id, access).await?;
+                if_match.check(&before)?;
+                let row = client
+                    .query(
+                        "select (select count(*) from ConsOrderItem where ConsID = @P1) as Items, \
+                        (select count(*) from ConsPayment where ConsID = @P1) as Payments",
+                        &[&id],
+                    )
+                    .await?
+                    .into_row()
+                    .await?;
+                let (items, payments) = match row {
+                    Some(row) => (
+                        row.try_get_value::<i32>("Items")?,
+                        row.try_get_value::<i32>("Payments")?,
+                    ),
+                    None => (0, 0),
+                };
+
+                if items + payments > 0 {
+                    if !cascade {
+                        bail!(RecordInUse("order items or payments"))
+                    }
+                    let items: Vec<Row> = client
+                        .query(
+                            "select ItemID, ConsID, Num, CatCode, AccountGrn, AccountPrice, ManualFix \
+                            from ConsOrderItem where ConsID = @P1",
+                            &[&id],
+                        )
+                        .await?
+                        .into_first_result()
+                        .await?;
+                    for row in &items {
+                        let item = Self::try_map_order_item(row)?;
+                        Self::write_audit(client, audit.deleted(entity::ORDER_ITEM, item.itemId, &item)?)
+                            .await?;
+                    }
+                    let payments: Vec<Row> = client
+                        .query(
+                            "select PayID, ConsID, PayDate, PaidGrn, PayDocNum from ConsPayment where ConsID = @P1",
+                            &[&id],
+                        )
+                        .await?
+                        .into_first_result()
+                        .await?;
+                    for row in &payments {
+                        let payment = Self::try_map_payment(row)?;
+                        Self::write_audit(client, audit.deleted(entity::PAYMENT, payment.payId, &payment)?)
+                            .await?;
+                    }
+                    client
+                        .execute("DELETE from ConsOrderItem where ConsID = @P1", &[&id])
+                        .await?;
+                    client
+                        .execute("DELETE from ConsPayment where ConsID = @P1", &[&id])
+                        .await?;
+                }
+
+                let result = client
+                    .execute("DELETE from ConsOrders where ConsID = @P1", &[&id])
+                    .await?;
+
+                if let Some(count) = result.rows_affected().first()
+                    && count > &0
+                {
+                    return Self::write_audit(client, audit.deleted(entity::ORDER, id, &before)?)
+                        .await;
+                }
+
+                bail!(DBRecordNotFound)
+            })
+            .await
+        })
+        .await
+    }
 
-        let mut client = self.db_pool.get().await?;
+    pub async fn get_category(&self, id: i32) -> Result<Category> {
+        metrics::sql("get_category", async {
+            let mut client = self.connection().await?;
+            Self::fetch_category(&mut client, id).await
+        })
+        .await
+    }
 
-        let stream = client
-            .query("SELECT * from ConsOrders where ConsID = @P1", &[&id])
+    pub async fn get_categories(&self, page: PageRequest) -> Result<Page<Category>> {
+        metrics::sql("get_categories", async {
+            let mut client = self.connection().await?;
+
+            let mut builder = QueryBuilder::new();
+            let total = Self::count(
+                &mut client,
+                builder.build(format!(
+                    "select count(*) as Total from ConsCats{}",
+                    builder.where_clause()
+                )),
+            )
             .await?;
-        let row = stream.into_row().await?;
 
-        if let Some(order_row) = row {
-            let order = Self::try_map_order(&order_row)?;
-            return Ok(order);
-        }
+            page.keyset(&mut builder, "CatID");
+            let query_sql = format!(
+                "SELECT * from ConsCats{} order by CatID{}",
+                builder.where_clause(),
+                page.fetch_clause(&mut builder)
+            );
+            let stream = builder.build(query_sql).query(&mut client).await?;
+            let rows: Vec<Row> = stream.into_first_result().await?;
+            metrics::record_rows(rows.len());
+
+            let cats: Vec<_> = rows
+                .iter()
+                .map(Self::try_map_category)
+                .collect::<Result<_>>()?;
+            info!("Cats count = {}", cats.len());
 
-        bail!(DBRecordNotFound)
+            Ok(Page::new(cats, total, page, |cat| cat.catId))
+        })
+        .await
     }
 
-    pub async fn create_order(&self, create_order: CreateOrder) -> Result<Order> {
-        let mut client = self.db_pool.get().await?;
-        let result = client.query(
-                "declare @rc int; exec @rc = up_NewAccount @P1, @P2, @P3, @P4, @P5, @P6, @P7, @P8, @P9, @P10; select @rc as Id", 
-                &[&create_order.accountNum,
-                &create_order.accountDate,
-                &create_order.incomeDate,
-                &create_order.hasTrust,
-                &create_order.trustSer,
-                &create_order.trustNum,
-                &create_order.supplierId,
-                &create_order.bySelf,
-                &create_order.comment,
-                &create_order.enterpriseId])
-            .await?
-            .into_row()
+    pub async fn create_category(
+        &self,
+        create_cat: CreateCategory,
+        audit: &Audit,
+    ) -> Result<Category> {
+        metrics::sql("create_category", async {
+            let mut client = self.connection().await?;
+            Self::in_transaction(&mut client, async |client| {
+                let result = client.query(
+                        "insert into ConsCats (ParentID, CatName, CatUnitCode, Code) values (@P1, @P2, @P3, @P4); select CAST(SCOPE_IDENTITY() as int) as Id", 
+                        &[&create_cat.parentId,
+                        &create_cat.catName,
+                        &create_cat.catUnitCode,
+                        &create_cat.code])
+                    .await?
+                    .into_row()
+                    .await?;
+
+                if let Some(row) = result {
+                    let id_value = row.try_get::<i32, &str>("Id")?;
+                    if let Some(id) = id_value {
+                        let cat = Self::fetch_category(client, id).await?;
+                        Self::write_audit(client, audit.created(entity::CATEGORY, id, &cat)?).await?;
+                        return Ok(cat);
+                    }
+                }
+
+                bail!(DBRecordNotFound)
+            })
+            .await
+        })
+        .await
+    }
+
+    pub async fn get_category_tree(&self) -> Result<Vec<CategoryNode>> {
+        metrics::sql("get_category_tree", async {
+            let mut client = self.connection().await?;
+
+            let stream = client
+                .simple_query("SELECT * from ConsCats order by CatID")
+                .await?;
+            let rows: Vec<Row> = stream.into_first_result().await?;
+            metrics::record_rows(rows.len());
+
+            let cats: Vec<_> = rows
+                .iter()
+                .map(Self::try_map_category)
+                .collect::<Result<_>>()?;
+            info!("Cats count = {}", cats.len());
+
+            Ok(category_tree::build_tree(cats))
+        })
+        .await
+    }
+
+    pub async fn get_category_descendants(&self, id: i32) -> Result<Vec<Category>> {
+        metrics::sql("get_category_descendants", async {
+            let mut client = self.connection().await?;
+
+            Self::fetch_category(&mut client, id).await?;
+
+            let stream = client
+                .query(
+                    format!("{CATEGORY_DESCENDANTS_SQL} select * from tree order by Depth, CatID"),
+                    &[&id],
+                )
+                .await?;
+            let rows: Vec<Row> = stream.into_first_result().await?;
+            metrics::record_rows(rows.len());
+
+            rows.iter().map(Self::try_map_category).collect()
+        })
+        .await
+    }
+
+    // Nearest parent goes first
+    pub async fn get_category_ancestors(&self, id: i32) -> Result<Vec<Category>> {
+        metrics::sql("get_category_ancestors", async {
+            let mut client = self.connection().await?;
+
+            let stream = client
+                .query(
+                    format!("{CATEGORY_ANCESTORS_SQL} select * from chain order by Depth"),
+                    &[&id],
+                )
+                .await?;
+            let rows: Vec<Row> = stream.into_first_result().await?;
+            metrics::record_rows(rows.len());
+
+            if rows.is_empty() {
+                bail!(DBRecordNotFound)
+            }
+
+            // the first row is the category itself
+            rows.iter().skip(1).map(Self::try_map_category).collect()
+        })
+        .await
+    }
+
+    pub async fn update_category(
+        &self,
+        id: i32,
+        cat: UpdateCategory,
+        if_match: &IfMatch,
+        audit: &Audit,
+    ) -> Result<Category> {
+        metrics::sql("update_category", async {
+            let mut builder = QueryBuilder::new();
+            builder.set_optional("ParentID", cat.parentId);
+            builder.set_optional("CatName", cat.catName);
+            builder.set_optional("CatUnitCode", cat.catUnitCode);
+            builder.set_optional("Code", cat.code);
+
+            let mut client = self.connection().await?;
+            if !builder.has_assignments() {
+                let current = Self::fetch_category(&mut client, id).await?;
+                if_match.check(&current)?;
+                return Ok(current);
+            }
+
+            builder.condition("CatID", "=", id);
+            let query_sql = format!(
+                "update ConsCats{}{}",
+                builder.set_clause(),
+                builder.where_clause()
+            );
+
+            Self::in_transaction(&mut client, async |client| {
+                let before = Self::lock_category(client, id).await?;
+                if_match.check(&before)?;
+                if let Some(Some(parent_id)) = cat.parentId {
+                    Self::ensure_valid_parent(client, id, parent_id).await?;
+                }
+
+                builder.build(query_sql).execute(client).await?;
+                let after = Self::fetch_category(client, id).await?;
+                Self::write_audit(
+                    client,
+                    audit.updated(entity::CATEGORY, id, &before, &after)?,
+                )
+                .await?;
+                Ok(after)
+            })
+            .await
+        })
+        .await
+    }
+
+    // Category with children is deleted only together with its whole subtree, when `cascade` is requested.
+    // The audit keeps the deleted category, not its descendants.
+    pub async fn delete_category(
+        &self,
+        id: i32,
+        cascade: bool,
+        if_match: &IfMatch,
+        audit: &Audit,
+    ) -> Result<()> {
+        metrics::sql("delete_category", async {
+            let mut client = self.connection().await?;
+
+            Self::in_transaction(&mut client, async |client| {
+                let before = Self::lock_category(client, id).await?;
+                if_match.check(&before)?;
+                let children = client
+                    .query(
+                        "select top (1) 1 from ConsCats where ParentID = @P1",
+                        &[&id],
+                    )
+                    .await?
+                    .into_row()
+                    .await?;
+
+                let result = if children.is_none() {
+                    client
+                        .execute("DELETE from ConsCats where CatID = @P1", &[&id])
+                        .await?
+                } else if cascade {
+                    let descendants: Vec<Row> = client
+                        .query(
+                            format!("{CATEGORY_DESCENDANTS_SQL} select * from tree"),
+                            &[&id],
+                        )
+                        .await?
+                        .into_first_result()
+                        .await?;
+                    for row in &descendants {
+                        let category = Self::try_map_category(row)?;
+                        Self::write_audit(
+                            client,
+                            audit.deleted(entity::CATEGORY, category.catId, &category)?,
+                        )
+                        .await?;
+                    }
+                    client
+                        .execute(
+                            format!(
+                                "{CATEGORY_DESCENDANTS_SQL} \
+                                DELETE from ConsCats where CatID = @P1 or CatID in (select CatID from tree)"
+                            ),
+                            &[&id],
+                        )
+                        .await?
+                } else {
+                    bail!(RecordInUse("child categories"))
+                };
+
+                if let Some(count) = result.rows_affected().first()
+                    && count > &0
+                {
+                    return Self::write_audit(client, audit.deleted(entity::CATEGORY, id, &before)?)
+                        .await;
+                }
+
+                bail!(DBRecordNotFound)
+            })
+            .await
+        })
+        .await
+    }
+
+    pub async fn get_suppliers(
+        &self,
+        filter: SupplierFilter,
+        page: PageRequest,
+    ) -> Result<Page<Supplier>> {
+        metrics::sql("get_suppliers", async {
+            let mut client = self.connection().await?;
+
+            let mut builder = QueryBuilder::new();
+            if let Some(q) = filter.q.filter(|q| !q.trim().is_empty()) {
+                let pattern = builder.param(contains_pattern(&q.trim().to_uppercase()));
+                builder.raw_condition(&format!(
+                    "(UPPER(SellerName) like {pattern} escape '\\' \
+                    or UPPER(SellerFullName) like {pattern} escape '\\' \
+                    or UPPER(SellerManager) like {pattern} escape '\\' \
+                    or UPPER(SellerEmail) like {pattern} escape '\\')"
+                ));
+            }
+            let total = Self::count(
+                &mut client,
+                builder.build(format!(
+                    "select count(*) as Total from Seller{}",
+                    builder.where_clause()
+                )),
+            )
             .await?;
 
-        if let Some(row) = result {
-            let id_value = row.try_get::<i32, &str>("Id")?;
-            if let Some(id) = id_value {
-                let order = self.get_order(id).await?;
-                return Ok(order);
+            page.keyset(&mut builder, "SellerID");
+            let query_sql = format!(
+                "SELECT * from Seller{} order by SellerID{}",
+                builder.where_clause(),
+                page.fetch_clause(&mut builder)
+            );
+            let stream = builder.build(query_sql).query(&mut client).await?;
+            let rows: Vec<Row> = stream.into_first_result().await?;
+            metrics::record_rows(rows.len());
+
+            let list: Vec<_> = rows
+                .iter()
+                .map(Self::try_map_supplier)
+                .collect::<Result<_>>()?;
+            info!("Suppliers count = {}", list.len());
+
+            Ok(Page::new(list, total, page, |supplier| supplier.supplierId))
+        })
+        .await
+    }
+
+    pub async fn get_supplier_by_id(&self, id: i32) -> Result<Supplier> {
+        metrics::sql("get_supplier_by_id", async {
+            let mut client = self.connection().await?;
+            Self::fetch_supplier(&mut client, id).await
+        })
+        .await
+    }
+
+    pub async fn get_supplier_by_name(&self, name: String) -> Result<Supplier> {
+        metrics::sql("get_supplier_by_name", async {
+            let mut client = self.connection().await?;
+
+            let stream = client
+                .query("SELECT * from Seller where SellerName = @P1", &[&name])
+                .await?;
+            let row = stream.into_row().await?;
+
+            if let Some(seller_row) = row {
+                let seller = Self::try_map_supplier(&seller_row)?;
+                return Ok(seller);
             }
-        }
 
-        bail!(DBRecordNotFound)
+            bail!(DBRecordNotFound)
+        })
+        .await
     }
 
-    pub async fn get_category(&self, id: i32) -> Result<Category> {
-        let mut client = self.db_pool.get().await?;
+    pub async fn create_supplier(
+        &self,
+        create_supplier: CreateSupplier,
+        audit: &Audit,
+    ) -> Result<Supplier> {
+        metrics::sql("create_supplier", async {
+            let mut client = self.connection().await?;
+            Self::in_transaction(&mut client, async |client| {
+                let result = client.query(
+                        "insert into Seller (SellerName, SellerPhone, SellerFax, SellerManager, SellerEmail, SellerAddressDoc, SellerAddressFact, SellerAddressStore, SellerStoreTime, SellerStoreWho, SellerStorePhone, SellerFullName) \
+                        values (@P1, @P2, @P3, @P4, @P5, @P6, @P7, @P8, @P9, @P10, @P11, @P12); select CAST(SCOPE_IDENTITY() as int) as Id", 
+                        &[&create_supplier.supplierName,
+                        &create_supplier.supplierPhone,
+                        &create_supplier.supplierFax,
+                        &create_supplier.supplierManager,
+                        &create_supplier.supplierEmail,
+                        &create_supplier.supplierAddressDoc,
+                        &create_supplier.supplierAddressFact,
+                        &create_supplier.supplierAddressStore,
+                        &create_supplier.supplierStoreTime,
+                        &create_supplier.supplierStoreWho,
+                        &create_supplier.supplierStorePhone,
+                        &create_supplier.supplierFullName
+                        ])
+                    .await?
+                    .into_row()
+                    .await?;
+
+                if let Some(row) = result {
+                    //debug!("{:?}", r);
+                    let id_value = row.try_get::<i32, &str>("Id")?;
+                    if let Some(id) = id_value {
+                        let supplier = Self::fetch_supplier(client, id).await?;
+                        Self::write_audit(client, audit.created(entity::SUPPLIER, id, &supplier)?)
+                            .await?;
+                        return Ok(supplier);
+                    }
+                }
+
+                bail!(DBRecordNotFound)
+            })
+            .await
+        })
+        .await
+    }
+
+    pub async fn update_supplier(
+        &self,
+        id: i32,
+        supplier: CreateSupplier,
+        if_match: &IfMatch,
+        audit: &Audit,
+    ) -> Result<Supplier> {
+        metrics::sql("update_supplier", async {
+            let mut client = self.connection().await?;
+
+            Self::in_transaction(&mut client, async |client| {
+                let before = Self::lock_supplier(client, id).await?;
+                if_match.check(&before)?;
+                client.execute(
+                        "update Seller set SellerName = @P2, SellerPhone = @P3, SellerFax = @P4, SellerManager = @P5, SellerEmail = @P6, \
+                        SellerAddressDoc = @P7, SellerAddressFact = @P8, SellerAddressStore = @P9, SellerStoreTime = @P10, \
+                        SellerStoreWho = @P11, SellerStorePhone = @P12, SellerFullName = @P13 \
+                        where SellerID = @P1",
+                        &[&id,
+                        &supplier.supplierName,
+                        &supplier.supplierPhone,
+                        &supplier.supplierFax,
+                        &supplier.supplierManager,
+                        &supplier.supplierEmail,
+                        &supplier.supplierAddressDoc,
+                        &supplier.supplierAddressFact,
+                        &supplier.supplierAddressStore,
+                        &supplier.supplierStoreTime,
+                        &supplier.supplierStoreWho,
+                        &supplier.supplierStorePhone,
+                        &supplier.supplierFullName
+                        ])
+                    .await?;
+
+                let after = Self::fetch_supplier(client, id).await?;
+                Self::write_audit(client, audit.updated(entity::SUPPLIER, id, &before, &after)?).await?;
+                Ok(after)
+            })
+            .await
+        })
+        .await
+    }
+
+    pub async fn patch_supplier(
+        &self,
+        id: i32,
+        supplier: UpdateSupplier,
+        if_match: &IfMatch,
+        audit: &Audit,
+    ) -> Result<Supplier> {
+        metrics::sql("patch_supplier", async {
+            let mut builder = QueryBuilder::new();
+            builder.set_optional("SellerName", supplier.supplierName);
+            builder.set_optional("SellerPhone", supplier.supplierPhone);
+            builder.set_optional("SellerFax", supplier.supplierFax);
+            builder.set_optional("SellerManager", supplier.supplierManager);
+            builder.set_optional("SellerEmail", supplier.supplierEmail);
+            builder.set_optional("SellerAddressDoc", supplier.supplierAddressDoc);
+            builder.set_optional("SellerAddressFact", supplier.supplierAddressFact);
+            builder.set_optional("SellerAddressStore", supplier.supplierAddressStore);
+            builder.set_optional("SellerStoreTime", supplier.supplierStoreTime);
+            builder.set_optional("SellerStoreWho", supplier.supplierStoreWho);
+            builder.set_optional("SellerStorePhone", supplier.supplierStorePhone);
+            builder.set_optional("SellerFullName", supplier.supplierFullName);
+
+            let mut client = self.connection().await?;
+            if !builder.has_assignments() {
+                let current = Self::fetch_supplier(&mut client, id).await?;
+                if_match.check(&current)?;
+                return Ok(current);
+            }
+
+            builder.condition("SellerID", "=", id);
+            let query_sql = format!(
+                "update Seller{}{}",
+                builder.set_clause(),
+                builder.where_clause()
+            );
+
+            Self::in_transaction(&mut client, async |client| {
+                let before = Self::lock_supplier(client, id).await?;
+                if_match.check(&before)?;
+                builder.build(query_sql).execute(client).await?;
+                let after = Self::fetch_supplier(client, id).await?;
+                Self::write_audit(
+                    client,
+                    audit.updated(entity::SUPPLIER, id, &before, &after)?,
+                )
+                .await?;
+                Ok(after)
+            })
+            .await
+        })
+        .await
+    }
+
+    pub async fn delete_supplier(&self, id: i32, if_match: &IfMatch, audit: &Audit) -> Result<()> {
+        metrics::sql("delete_supplier", async {
+            let mut client = self.connection().await?;
+
+            Self::in_transaction(&mut client, async |client| {
+                let before = Self::lock_supplier(client, id).await?;
+                if_match.check(&before)?;
+
+                let orders = client
+                    .query(
+                        "select top (1) 1 from ConsOrders where SellerID = @P1",
+                        &[&id],
+                    )
+                    .await?
+                    .into_row()
+                    .await?;
+                if orders.is_some() {
+                    bail!(RecordInUse("orders"))
+                }
+
+                client
+                    .execute("DELETE from Seller where SellerID = @P1", &[&id])
+                    .await?;
+                Self::write_audit(client, audit.deleted(entity::SUPPLIER, id, &before)?).await
+            })
+            .await
+        })
+        .await
+    }
+
+    pub async fn get_order_items(
+        &self,
+        order_id: i32,
+        access: EnterpriseAccess,
+    ) -> Result<Vec<OrderItem>> {
+        metrics::sql("get_order_items", async {
+            let mut client = self.connection().await?;
+
+            Self::ensure_visible_order(&mut client, order_id, access).await?;
+
+            let stream = client
+                .query(
+                    "select ItemID, ConsID, Num, CatCode, AccountGrn, AccountPrice, ManualFix \
+                    from ConsOrderItem where ConsID = @P1 order by ItemID",
+                    &[&order_id],
+                )
+                .await?;
+            let rows: Vec<Row> = stream.into_first_result().await?;
+            metrics::record_rows(rows.len());
+
+            let items: Result<Vec<_>> = rows.iter().map(Self::try_map_order_item).collect();
+
+            if let Ok(list) = items {
+                info!("Order items count = {}", list.len());
+                return Ok(list);
+            }
+
+            items
+        })
+        .await
+    }
+
+    pub async fn get_order_item(
+        &self,
+        order_id: i32,
+        item_id: i32,
+        access: EnterpriseAccess,
+    ) -> Result<OrderItem> {
+        metrics::sql("get_order_item", async {
+            let mut client = self.connection().await?;
+            Self::ensure_visible_order(&mut client, order_id, access).await?;
+            Self::fetch_order_item(&mut client, order_id, item_id).await
+        })
+        .await
+    }
+
+    pub async fn create_order_item(
+        &self,
+        order_id: i32,
+        create_item: CreateOrderItem,
+        access: EnterpriseAccess,
+        audit: &Audit,
+    ) -> Result<OrderItem> {
+        metrics::sql("create_order_item", async {
+            let mut client = self.connection().await?;
+
+            Self::ensure_visible_order(&mut client, order_id, access).await?;
+            Self::ensure_category_code(&mut client, create_item.catCode).await?;
+
+            Self::in_transaction(&mut client, async |client| {
+                let result = client.query(
+                        "insert into ConsOrderItem (ConsID, Num, CatCode, AccountGrn, AccountPrice, ManualFix) \
+                        values (@P1, @P2, @P3, @P4, @P5, @P6); select CAST(SCOPE_IDENTITY() as int) as Id",
+                        &[&order_id,
+                        &create_item.num,
+                        &create_item.catCode,
+                        &create_item.accountGrn,
+                        &create_item.accountPrice,
+                        &create_item.manualFix])
+                    .await?
+                    .into_row()
+                    .await?;
+
+                if let Some(row) = result {
+                    let id_value = row.try_get::<i32, &str>("Id")?;
+                    if let Some(id) = id_value {
+                        let item = Self::fetch_order_item(client, order_id, id).await?;
+                        Self::write_audit(client, audit.created(entity::ORDER_ITEM, id, &item)?).await?;
+                        return Ok(item);
+                    }
+                }
+
+                bail!(DBRecordNotFound)
+            })
+            .await
+        })
+        .await
+    }
+
+    pub async fn update_order_item(
+        &self,
+        order_id: i32,
+        item_id: i32,
+        item: CreateOrderItem,
+        if_match: &IfMatch,
+        access: EnterpriseAccess,
+        audit: &Audit,
+    ) -> Result<OrderItem> {
+        metrics::sql("update_order_item", async {
+            let mut client = self.connection().await?;
+
+            Self::ensure_visible_order(&mut client, order_id, access).await?;
+            Self::ensure_category_code(&mut client, item.catCode).await?;
+
+            Self::in_transaction(&mut client, async |client| {
+                let before = Self::lock_order_item(client, order_id, item_id).await?;
+                if_match.check(&before)?;
+                let result = client
+                    .execute(
+                        "update ConsOrderItem set Num = @P3, CatCode = @P4, AccountGrn = @P5, AccountPrice = @P6, ManualFix = @P7 \
+                        where ConsID = @P1 and ItemID = @P2",
+                        &[&order_id,
+                        &item_id,
+                        &item.num,
+                        &item.catCode,
+                        &item.accountGrn,
+                        &item.accountPrice,
+                        &item.manualFix],
+                    )
+                    .await?;
+
+                if let Some(count) = result.rows_affected().first()
+                    && count > &0
+                {
+                    let after = Self::fetch_order_item(client, order_id, item_id).await?;
+                    Self::write_audit(
+                        client,
+                        audit.updated(entity::ORDER_ITEM, item_id, &before, &after)?,
+                    )
+                    .await?;
+                    return Ok(after);
+                }
+
+                bail!(DBRecordNotFound)
+            })
+            .await
+        })
+        .await
+    }
 
+    pub async fn delete_order_item(
+        &self,
+        order_id: i32,
+        item_id: i32,
+        if_match: &IfMatch,
+        access: EnterpriseAccess,
+        audit: &Audit,
+    ) -> Result<()> {
+        metrics::sql("delete_order_item", async {
+            let mut client = self.connection().await?;
+            Self::ensure_visible_order(&mut client, order_id, access).await?;
+
+            Self::in_transaction(&mut client, async |client| {
+                let before = Self::lock_order_item(client, order_id, item_id).await?;
+                if_match.check(&before)?;
+                let result = client
+                    .execute(
+                        "DELETE from ConsOrderItem where ConsID = @P1 and ItemID = @P2",
+                        &[&order_id, &item_id],
+                    )
+                    .await?;
+
+                if let Some(count) = result.rows_affected().first()
+                    && count > &0
+                {
+                    return Self::write_audit(
+                        client,
+                        audit.deleted(entity::ORDER_ITEM, item_id, &before)?,
+                    )
+                    .await;
+                }
+
+                bail!(DBRecordNotFound)
+            })
+            .await
+        })
+        .await
+    }
+
+    // Runs `f` in a transaction, which is committed only if `f` succeeds.
+    // A transaction left open by a dropped request is rolled back when the pool
+    // checks the connection out again, see `TiberiusConnection::is_valid`
+    async fn in_transaction<T>(
+        client: &mut Connection,
+        f: impl AsyncFnOnce(&mut Connection) -> Result<T>,
+    ) -> Result<T> {
+        client
+            .execute("SET XACT_ABORT ON; BEGIN TRANSACTION", &[])
+            .await?;
+
+        match f(client).await {
+            Ok(value) => {
+                client
+                    .execute("COMMIT TRANSACTION; SET XACT_ABORT OFF", &[])
+                    .await?;
+                Ok(value)
+            }
+            Err(e) => {
+                if let Err(rollback_err) = client
+                    .execute(
+                        "IF @@TRANCOUNT > 0 ROLLBACK TRANSACTION; SET XACT_ABORT OFF",
+                        &[],
+                    )
+                    .await
+                {
+                    error!("Failed to roll back transaction: {rollback_err}");
+                }
+                Err(e)
+            }
+        }
+    }
+
+    async fn fetch_category(client: &mut Connection, id: i32) -> Result<Category> {
         let stream = client
             .query("SELECT * from ConsCats where CatID = @P1", &[&id])
             .await?;
@@ -207,64 +1267,51 @@ impl DB {
         bail!(DBRecordNotFound)
     }
 
-    pub async fn get_categories(&self) -> Result<Vec<Category>> {
-        let mut client = self.db_pool.get().await?;
-
-        let stream = client.simple_query("SELECT * from ConsCats").await?;
-        let rows: Vec<Row> = stream.into_first_result().await?;
-
-        let mapped_cats: Result<Vec<_>> = rows.iter().map(Self::try_map_category).collect();
-
-        if let Ok(cats) = mapped_cats {
-            info!("Cats count = {}", cats.len());
-            return Ok(cats);
+    // New parent must exist and must not be the category itself or any of its descendants
+    async fn ensure_valid_parent(client: &mut Connection, id: i32, parent_id: i32) -> Result<()> {
+        if parent_id == id {
+            bail!(CategoryCycle { id, parent_id })
         }
 
-        mapped_cats
-    }
-
-    pub async fn create_category(&self, create_cat: CreateCategory) -> Result<Category> {
-        let mut client = self.db_pool.get().await?;
-        let result = client.query(
-                "insert into ConsCats (ParentID, CatName, CatUnitCode, Code) values (@P1, @P2, @P3, @P4); select CAST(SCOPE_IDENTITY() as int) as Id", 
-                &[&create_cat.parentId,
-                &create_cat.catName,
-                &create_cat.catUnitCode,
-                &create_cat.code])
+        let parent = client
+            .query("select 1 from ConsCats where CatID = @P1", &[&parent_id])
             .await?
             .into_row()
             .await?;
+        if parent.is_none() {
+            bail!(UnknownParentCategory(parent_id))
+        }
 
-        if let Some(row) = result {
-            let id_value = row.try_get::<i32, &str>("Id")?;
-            if let Some(id) = id_value {
-                let cat = self.get_category(id).await?;
-                return Ok(cat);
-            }
+        let descendant = client
+            .query(
+                format!("{CATEGORY_DESCENDANTS_SQL} select 1 from tree where CatID = @P2"),
+                &[&id, &parent_id],
+            )
+            .await?
+            .into_row()
+            .await?;
+        if descendant.is_some() {
+            bail!(CategoryCycle { id, parent_id })
         }
 
-        bail!(DBRecordNotFound)
+        Ok(())
     }
 
-    pub async fn delete_category(&self, id: i32) -> Result<()> {
-        let mut client = self.db_pool.get().await?;
-
-        let result = client
-            .execute("DELETE from ConsCats where CatID = @P1", &[&id])
+    async fn fetch_order(client: &mut Connection, id: i32) -> Result<Order> {
+        let stream = client
+            .query("SELECT * from ConsOrders where ConsID = @P1", &[&id])
             .await?;
+        let row = stream.into_row().await?;
 
-        if let Some(count) = result.rows_affected().first()
-            && count > &0
-        {
-            return Ok(());
+        if let Some(order_row) = row {
+            let order = Self::try_map_order(&order_row)?;
+            return Ok(order);
         }
 
         bail!(DBRecordNotFound)
     }
 
-    pub async fn get_supplier_by_id(&self, id: i32) -> Result<Supplier> {
-        let mut client = self.db_pool.get().await?;
-
+    async fn fetch_supplier(client: &mut Connection, id: i32) -> Result<Supplier> {
         let stream = client
             .query("SELECT * from Seller where SellerID = @P1", &[&id])
             .await?;
@@ -278,76 +1325,659 @@ impl DB {
         bail!(DBRecordNotFound)
     }
 
-    pub async fn get_supplier_by_name(&self, name: String) -> Result<Supplier> {
-        let mut client = self.db_pool.get().await?;
+    // `lock_*` read the record to compare with `If-Match` and keep it locked
+    // until the end of the transaction, so it cannot change in between
+    async fn lock_order(client: &mut Connection, id: i32) -> Result<Order> {
+        let row = client
+            .query(
+                "SELECT * from ConsOrders with (updlock, rowlock) where ConsID = @P1",
+                &[&id],
+            )
+            .await?
+            .into_row()
+            .await?;
 
-        let stream = client
-            .query("SELECT * from Seller where SellerName = @P1", &[&name])
+        match row {
+            Some(row) => Self::try_map_order(&row),
+            None => bail!(DBRecordNotFound),
+        }
+    }
+
+    // Orders of other enterprises are reported as not found
+    async fn lock_visible_order(
+        client: &mut Connection,
+        id: i32,
+        access: EnterpriseAccess,
+    ) -> Result<Order> {
+        let order = Self::lock_order(client, id).await?;
+        if !access.can_read(order.enterpriseId)? {
+            bail!(DBRecordNotFound)
+        }
+        Ok(order)
+    }
+
+    async fn lock_category(client: &mut Connection, id: i32) -> Result<Category> {
+        let row = client
+            .query(
+                "SELECT * from ConsCats with (updlock, rowlock) where CatID = @P1",
+                &[&id],
+            )
+            .await?
+            .into_row()
             .await?;
-        let row = stream.into_row().await?;
 
-        if let Some(seller_row) = row {
-            let seller = Self::try_map_supplier(&seller_row)?;
-            return Ok(seller);
+        match row {
+            Some(row) => Self::try_map_category(&row),
+            None => bail!(DBRecordNotFound),
+        }
+    }
+
+    async fn lock_supplier(client: &mut Connection, id: i32) -> Result<Supplier> {
+        let row = client
+            .query(
+                "SELECT * from Seller with (updlock, rowlock) where SellerID = @P1",
+                &[&id],
+            )
+            .await?
+            .into_row()
+            .await?;
+
+        match row {
+            Some(row) => Self::try_map_supplier(&row),
+            None => bail!(DBRecordNotFound),
         }
+    }
 
-        bail!(DBRecordNotFound)
+    async fn count(client: &mut Connection, query: Query<'_>) -> Result<i32> {
+        let row = query.query(client).await?.into_row().await?;
+        match row {
+            Some(row) => row.try_get_value("Total"),
+            None => Ok(0),
+        }
+    }
+
+    // Items and payments of orders of other enterprises are reported as not found,
+    // as the orders themselves in `lock_visible_order`
+    async fn ensure_visible_order(
+        client: &mut Connection,
+        id: i32,
+        access: EnterpriseAccess,
+    ) -> Result<()> {
+        let row = client
+            .query(
+                "select EnterpriseID from ConsOrders where ConsID = @P1",
+                &[&id],
+            )
+            .await?
+            .into_row()
+            .await?;
+        let enterprise_id = row
+            .map(|row| row.try_get_value("EnterpriseID"))
+            .transpose()?;
+        Self::check_visible(enterprise_id, access)
+    }
+
+    fn check_visible(enterprise_id: Option<i32>, access: EnterpriseAccess) -> Result<()> {
+        match enterprise_id {
+            Some(id) if access.can_read(id)? => Ok(()),
+            _ => bail!(DBRecordNotFound),
+        }
     }
 
-    pub async fn create_supplier(&self, create_supplier: CreateSupplier) -> Result<Supplier> {
-        let mut client = self.db_pool.get().await?;
-        let result = client.query(
-                "insert into Seller (SellerName, SellerPhone, SellerFax, SellerManager, SellerEmail, SellerAddressDoc, SellerAddressFact, SellerAddressStore, SellerStoreTime, SellerStoreWho, SellerStorePhone, SellerFullName) \
-                values (@P1, @P2, @P3, @P4, @P5, @P6, @P7, @P8, @P9, @P10, @P11, @P12); select CAST(SCOPE_IDENTITY() as int) as Id", 
-                &[&create_supplier.supplierName,
-                &create_supplier.supplierPhone,
-                &create_supplier.supplierFax,
-                &create_supplier.supplierManager,
-                &create_supplier.supplierEmail,
-                &create_supplier.supplierAddressDoc,
-                &create_supplier.supplierAddressFact,
-                &create_supplier.supplierAddressStore,
-                &create_supplier.supplierStoreTime,
-                &create_supplier.supplierStoreWho,
-                &create_supplier.supplierStorePhone,
-                &create_supplier.supplierFullName
-                ])
+    async fn ensure_category_code(client: &mut Connection, code: i32) -> Result<()> {
+        let row = client
+            .query("select 1 from ConsCats where Code = @P1", &[&code])
             .await?
             .into_row()
             .await?;
 
-        if let Some(row) = result {
-            //debug!("{:?}", r);
-            let id_value = row.try_get::<i32, &str>("Id")?;
-            if let Some(id) = id_value {
-                let supplier = self.get_supplier_by_id(id).await?;
-                return Ok(supplier);
-            }
+        if row.is_none() {
+            bail!(UnknownCategoryCode(code))
         }
 
-        bail!(DBRecordNotFound)
+        Ok(())
+    }
+
+    async fn fetch_order_item(
+        client: &mut Connection,
+        order_id: i32,
+        item_id: i32,
+    ) -> Result<OrderItem> {
+        let row = client
+            .query(
+                "select ItemID, ConsID, Num, CatCode, AccountGrn, AccountPrice, ManualFix \
+                from ConsOrderItem where ConsID = @P1 and ItemID = @P2",
+                &[&order_id, &item_id],
+            )
+            .await?
+            .into_row()
+            .await?;
+
+        match row {
+            Some(row) => Self::try_map_order_item(&row),
+            None => bail!(DBRecordNotFound),
+        }
+    }
+
+    async fn lock_order_item(
+        client: &mut Connection,
+        order_id: i32,
+        item_id: i32,
+    ) -> Result<OrderItem> {
+        let row = client
+            .query(
+                "select ItemID, ConsID, Num, CatCode, AccountGrn, AccountPrice, ManualFix \
+                from ConsOrderItem with (updlock, rowlock) where ConsID = @P1 and ItemID = @P2",
+                &[&order_id, &item_id],
+            )
+            .await?
+            .into_row()
+            .await?;
+
+        match row {
+            Some(row) => Self::try_map_order_item(&row),
+            None => bail!(DBRecordNotFound),
+        }
+    }
+
+    async fn lock_payment(client: &mut Connection, id: i32) -> Result<Payment> {
+        let row = client
+            .query(
+                "select PayID, ConsID, PayDate, PaidGrn, PayDocNum from ConsPayment with (updlock, rowlock) \
+                where PayID = @P1",
+                &[&id],
+            )
+            .await?
+            .into_row()
+            .await?;
+
+        match row {
+            Some(row) => Self::try_map_payment(&row),
+            None => bail!(DBRecordNotFound),
+        }
+    }
+
+    async fn fetch_payment(client: &mut Connection, id: i32) -> Result<Payment> {
+        let row = client
+            .query(
+                "select PayID, ConsID, PayDate, PaidGrn, PayDocNum from ConsPayment where PayID = @P1",
+                &[&id],
+            )
+            .await?
+            .into_row()
+            .await?;
+
+        match row {
+            Some(row) => Self::try_map_payment(&row),
+            None => bail!(DBRecordNotFound),
+        }
+    }
+
+    async fn fetch_request(client: &mut Connection, id: i32) -> Result<ConsRequest> {
+        let row = client
+            .query(
+                "select ReqID, RequestState, RequestDate, UserCode, CatCode, NeedDate, Num, CancelRequest, RefuseRequest \
+                from ConsReqs where ReqID = @P1",
+                &[&id],
+            )
+            .await?
+            .into_row()
+            .await?;
+
+        match row {
+            Some(row) => Self::try_map_request(&row),
+            None => bail!(DBRecordNotFound),
+        }
+    }
+
+    // Must run in the transaction of the change, so the change is not kept without its audit record
+    async fn write_audit(client: &mut Connection, record: AuditRecord<'_>) -> Result<()> {
+        client
+            .execute(
+                "insert into ApiAudit (AuditDate, UserID, Route, Entity, EntityID, Before, After) \
+                values (getutcdate(), @P1, @P2, @P3, @P4, @P5, @P6)",
+                &[
+                    &record.user_id,
+                    &record.route,
+                    &record.entity,
+                    &record.entity_id,
+                    &record.before,
+                    &record.after,
+                ],
+            )
+            .await?;
+        Ok(())
+    }
+
+    pub async fn get_payments(&self, access: EnterpriseAccess) -> Result<Vec<PaymentView>> {
+        metrics::sql("get_payments", async {
+            let (builder, query_sql) = Self::payments_query(access)?;
+
+            let mut client = self.connection().await?;
+            let stream = builder.build(query_sql).query(&mut client).await?;
+            let rows: Vec<Row> = stream.into_first_result().await?;
+            metrics::record_rows(rows.len());
+
+            let payments: Result<Vec<_>> = rows.iter().map(Self::try_map_payment_view).collect();
+
+            if let Ok(list) = payments {
+                info!("Payments count = {}", list.len());
+                return Ok(list);
+            }
+
+            payments
+        })
+        .await
+    }
+
+    // Payments of the orders the user can see
+    fn payments_query(access: EnterpriseAccess) -> Result<(QueryBuilder, String)> {
+        let mut builder = QueryBuilder::new();
+        access.restrict(&mut builder, "cr.EnterpriseID")?;
+        let query_sql = format!(
+            "select PayID, cr.SellerID, PayDate, PaidGrn, cp.ConsID, AccountNum, PayDocNum \
+            from ConsPayment cp \
+            inner join ConsOrders cr on cp.ConsID = cr.ConsID \
+            left join Seller s on cr.SellerID = s.SellerID{} \
+            order by PayDate",
+            builder.where_clause()
+        );
+        Ok((builder, query_sql))
+    }
+
+    pub async fn get_order_payments(
+        &self,
+        order_id: i32,
+        access: EnterpriseAccess,
+    ) -> Result<Vec<Payment>> {
+        metrics::sql("get_order_payments", async {
+            let mut client = self.connection().await?;
+
+            Self::ensure_visible_order(&mut client, order_id, access).await?;
+
+            let stream = client
+                .query(
+                    "select PayID, ConsID, PayDate, PaidGrn, PayDocNum \
+                    from ConsPayment where ConsID = @P1 order by PayDate",
+                    &[&order_id],
+                )
+                .await?;
+            let rows: Vec<Row> = stream.into_first_result().await?;
+            metrics::record_rows(rows.len());
+
+            let payments: Result<Vec<_>> = rows.iter().map(Self::try_map_payment).collect();
+
+            if let Ok(list) = payments {
+                info!("Order payments count = {}", list.len());
+                return Ok(list);
+            }
+
+            payments
+        })
+        .await
+    }
+
+    pub async fn create_payment(
+        &self,
+        order_id: i32,
+        create_payment: CreatePayment,
+        access: EnterpriseAccess,
+        audit: &Audit,
+    ) -> Result<CreatedPayment> {
+        metrics::sql("create_payment", async {
+            let mut client = self.connection().await?;
+
+            Self::ensure_visible_order(&mut client, order_id, access).await?;
+
+            Self::in_transaction(&mut client, async |client| {
+                let stream = client.query(
+                        "insert into ConsPayment (ConsID, PayDate, PaidGrn, PayDocNum) values (@P1, @P2, @P3, @P4); \
+                        select PayID, ConsID, PayDate, PaidGrn, PayDocNum from ConsPayment where PayID = CAST(SCOPE_IDENTITY() as int); \
+                        select ISNULL((select sum(PaidGrn) from ConsPayment cp where cp.ConsID = @P1), 0) as PaidGrn",
+                        &[&order_id,
+                        &create_payment.payDate,
+                        &create_payment.paidGrn,
+                        &create_payment.payDocNum])
+                    .await?;
+                let mut results = stream.into_results().await?.into_iter();
+
+                let payment_row = results.next().and_then(|rows| rows.into_iter().next());
+                let total_row = results.next().and_then(|rows| rows.into_iter().next());
+
+                if let (Some(payment_row), Some(total_row)) = (payment_row, total_row) {
+                    let payment = Self::try_map_payment(&payment_row)?;
+                    Self::write_audit(client, audit.created(entity::PAYMENT, payment.payId, &payment)?)
+                        .await?;
+                    return Ok(CreatedPayment {
+                        payment,
+                        paidGrn: total_row.try_get_value("PaidGrn")?,
+                    });
+                }
+
+                bail!(DBRecordNotFound)
+            })
+            .await
+        })
+        .await
+    }
+
+    pub async fn get_payment(&self, id: i32, access: EnterpriseAccess) -> Result<Payment> {
+        metrics::sql("get_payment", async {
+            let mut client = self.connection().await?;
+            let payment = Self::fetch_payment(&mut client, id).await?;
+            Self::ensure_visible_order(&mut client, payment.consId, access).await?;
+            Ok(payment)
+        })
+        .await
+    }
+
+    pub async fn delete_payment(
+        &self,
+        id: i32,
+        if_match: &IfMatch,
+        access: EnterpriseAccess,
+        audit: &Audit,
+    ) -> Result<()> {
+        metrics::sql("delete_payment", async {
+            let mut client = self.connection().await?;
+
+            Self::in_transaction(&mut client, async |client| {
+                let before = Self::lock_payment(client, id).await?;
+                Self::ensure_visible_order(client, before.consId, access).await?;
+                if_match.check(&before)?;
+                let result = client
+                    .execute("DELETE from ConsPayment where PayID = @P1", &[&id])
+                    .await?;
+
+                if let Some(count) = result.rows_affected().first()
+                    && count > &0
+                {
+                    return Self::write_audit(client, audit.deleted(entity::PAYMENT, id, &before)?)
+                        .await;
+                }
+
+                bail!(DBRecordNotFound)
+            })
+            .await
+        })
+        .await
+    }
+
+    pub async fn get_open_requests(&self, filter: RequestFilter) -> Result<Vec<ConsRequest>> {
+        metrics::sql("get_open_requests", async {
+            let mut client = self.connection().await?;
+
+            let mut builder = QueryBuilder::new();
+            builder.raw_condition("CancelRequest = 0 and RefuseRequest = 0");
+            builder.optional("UserCode", "=", filter.userCode);
+            builder.optional("CatCode", "=", filter.catCode);
+            builder.optional("NeedDate", ">=", filter.needDateFrom);
+            builder.optional("NeedDate", "<=", filter.needDateTo);
+
+            let query_sql = format!(
+                "select ReqID, RequestState, RequestDate, UserCode, CatCode, NeedDate, Num, CancelRequest, RefuseRequest \
+                from ConsReqs{} order by RequestDate",
+                builder.where_clause()
+            );
+            let stream = builder.build(query_sql).query(&mut client).await?;
+            let rows: Vec<Row> = stream.into_first_result().await?;
+            metrics::record_rows(rows.len());
+
+            let requests: Result<Vec<_>> = rows.iter().map(Self::try_map_request).collect();
+
+            if let Ok(list) = requests {
+                info!("Requests count = {}", list.len());
+                return Ok(list);
+            }
+
+            requests
+        })
+        .await
+    }
+
+    pub async fn get_request(&self, id: i32) -> Result<ConsRequest> {
+        metrics::sql("get_request", async {
+            let mut client = self.connection().await?;
+            Self::fetch_request(&mut client, id).await
+        })
+        .await
+    }
+
+    pub async fn create_request(
+        &self,
+        create_request: CreateRequest,
+        user_code: i32,
+        audit: &Audit,
+    ) -> Result<ConsRequest> {
+        metrics::sql("create_request", async {
+            let mut client = self.connection().await?;
+
+            Self::ensure_category_code(&mut client, create_request.catCode).await?;
+
+            Self::in_transaction(&mut client, async |client| {
+                let result = client.query(
+                        "insert into ConsReqs (RequestState, RequestDate, UserCode, CatCode, NeedDate, Num, CancelRequest, RefuseRequest) \
+                        values (0, getdate(), @P1, @P2, @P3, @P4, 0, 0); select CAST(SCOPE_IDENTITY() as int) as Id",
+                        &[&user_code,
+                        &create_request.catCode,
+                        &create_request.needDate,
+                        &create_request.num])
+                    .await?
+                    .into_row()
+                    .await?;
+
+                if let Some(row) = result {
+                    let id_value = row.try_get::<i32, &str>("Id")?;
+                    if let Some(id) = id_value {
+                        let request = Self::fetch_request(client, id).await?;
+                        Self::write_audit(client, audit.created(entity::REQUEST, id, &request)?).await?;
+                        return Ok(request);
+                    }
+                }
+
+                bail!(DBRecordNotFound)
+            })
+            .await
+        })
+        .await
+    }
+
+    pub async fn cancel_request(
+        &self,
+        id: i32,
+        user_code: i32,
+        audit: &Audit,
+    ) -> Result<ConsRequest> {
+        metrics::sql("cancel_request", async {
+            self.close_request(id, user_code, "CancelRequest", "cancel", audit)
+                .await
+        })
+        .await
+    }
+
+    pub async fn refuse_request(
+        &self,
+        id: i32,
+        user_code: i32,
+        audit: &Audit,
+    ) -> Result<ConsRequest> {
+        metrics::sql("refuse_request", async {
+            self.close_request(id, user_code, "RefuseRequest", "refuse", audit)
+                .await
+        })
+        .await
+    }
+
+    // Only open requests can be cancelled or refused, the flag column keeps the code of the acting user
+    async fn close_request(
+        &self,
+        id: i32,
+        user_code: i32,
+        column: &str,
+        action: &'static str,
+        audit: &Audit,
+    ) -> Result<ConsRequest> {
+        let mut client = self.connection().await?;
+
+        Self::in_transaction(&mut client, async |client| {
+            let before = Self::fetch_request(client, id).await?;
+            let result = client
+                .execute(
+                    format!(
+                        "update ConsReqs set {column} = @P2 \
+                        where ReqID = @P1 and CancelRequest = 0 and RefuseRequest = 0"
+                    ),
+                    &[&id, &user_code],
+                )
+                .await?;
+
+            let request = Self::fetch_request(client, id).await?;
+            if let Some(count) = result.rows_affected().first()
+                && count > &0
+            {
+                Self::write_audit(
+                    client,
+                    audit.updated(entity::REQUEST, id, &before, &request)?,
+                )
+                .await?;
+                return Ok(request);
+            }
+
+            let state = if request.cancelRequest != 0 {
+                "cancelled"
+            } else {
+                "refused"
+            };
+            bail!(IllegalRequestTransition { action, state })
+        })
+        .await
+    }
+
+    pub async fn get_revoked_tokens(&self) -> Result<Vec<String>> {
+        metrics::sql("get_revoked_tokens", async {
+            let mut client = self.connection().await?;
+
+            let rows = client
+                .simple_query("select Jti from ApiRevokedToken")
+                .await?
+                .into_first_result()
+                .await?;
+            metrics::record_rows(rows.len());
+
+            rows.iter()
+                .map(|row| row.try_get_required::<&str>("Jti").map(str::to_owned))
+                .collect()
+        })
+        .await
+    }
+
+    // Issued tokens are not stored, the audit record keeps who was granted what
+    pub async fn record_issued_token(
+        &self,
+        jti: &str,
+        request: &CreateToken,
+        audit: &Audit,
+    ) -> Result<()> {
+        metrics::sql("record_issued_token", async {
+            let mut client = self.connection().await?;
+            Self::write_audit(&mut client, audit.created(entity::TOKEN, jti, request)?).await
+        })
+        .await
     }
 
-    // select PayID, cr.SellerID, PayDate, PaidGrn, cp.ConsID, AccountNum, PayDocNum
-    // from ConsPayment cp
-    //   inner join ConsOrders cr on cp.ConsID = cr.ConsID
-    //   left join Seller s on cr.SellerID = s.SellerID
-    // order by PayDate
+    // Revoking an already revoked token is not an error
+    pub async fn revoke_token(&self, jti: &str, audit: &Audit) -> Result<()> {
+        metrics::sql("revoke_token", async {
+            let mut client = self.connection().await?;
+
+            Self::in_transaction(&mut client, async |client| {
+                let result = client
+                    .execute(
+                        "insert into ApiRevokedToken (Jti, RevokedBy, RevokedAt) \
+                        select @P1, @P2, getdate() \
+                        where not exists (select 1 from ApiRevokedToken where Jti = @P1)",
+                        &[&jti, &audit.user_id()],
+                    )
+                    .await?;
+
+                if let Some(count) = result.rows_affected().first()
+                    && count > &0
+                {
+                    // An update of the issued token, told apart from its creation record
+                    Self::write_audit(
+                        client,
+                        audit.updated(entity::TOKEN, jti, &"active", &"revoked")?,
+                    )
+                    .await?;
+                }
+                Ok(())
+            })
+            .await
+        })
+        .await
+    }
 
-    // select ItemID, ConsID, Num, CatCode, AccountGrn, AccountPrice, ManualFix
-    // from ConsOrderItem
-    // where ConsID = @P1
-    // order by ItemID
+    pub async fn get_audit(
+        &self,
+        filter: AuditFilter,
+        page: PageRequest,
+    ) -> Result<Page<AuditEntry>> {
+        metrics::sql("get_audit", async {
+            let mut client = self.connection().await?;
+
+            let mut builder = QueryBuilder::new();
+            builder.optional("Entity", "=", filter.entity);
+            builder.optional("EntityID", "=", filter.id);
+            let total = Self::count(
+                &mut client,
+                builder.build(format!(
+                    "select count(*) as Total from ApiAudit{}",
+                    builder.where_clause()
+                )),
+            )
+            .await?;
 
-    // select PayID, ConsID, PayDate, PaidGrn, PayDocNum
-    // from ConsPayment
-    // where ConsID = @P1
-    // order by PayDate
+            page.keyset(&mut builder, "AuditID");
+            let query_sql = format!(
+                "select AuditID, AuditDate, UserID, Route, Entity, EntityID, Before, After \
+                from ApiAudit{} order by AuditID{}",
+                builder.where_clause(),
+                page.fetch_clause(&mut builder)
+            );
+            let stream = builder.build(query_sql).query(&mut client).await?;
+            let rows: Vec<Row> = stream.into_first_result().await?;
+            metrics::record_rows(rows.len());
+
+            let entries: Vec<_> = rows
+                .iter()
+                .map(Self::try_map_audit_entry)
+                .collect::<Result<_>>()?;
+            info!("Audit entries count = {}", entries.len());
+
+            Ok(Page::new(entries, total, page, |entry| entry.auditId))
+        })
+        .await
+    }
 
-    // select ReqID, RequestState, RequestDate, UserCode, CatCode, NeedDate, Num, CancelRequest, RefuseRequest
-    // from ConsReqs
-    // where CancelRequest = 0 and RefuseRequest = 0
-    // order by RequestDate
+    fn try_map_audit_entry(row: &Row) -> Result<AuditEntry> {
+        let json = |col: &str| -> Result<Option<serde_json::Value>> {
+            match row.try_get::<&str, &str>(col)? {
+                Some(value) => Ok(Some(serde_json::from_str(value)?)),
+                None => Ok(None),
+            }
+        };
+        let text = |col: &str| row.try_get_required::<&str>(col).map(str::to_owned);
+        Ok(AuditEntry {
+            auditId: row.try_get_required("AuditID")?,
+            auditDate: row.try_get_required("AuditDate")?,
+            userId: text("UserID")?,
+            route: text("Route")?,
+            entity: text("Entity")?,
+            entityId: text("EntityID")?,
+            before: json("Before")?,
+            after: json("After")?,
+        })
+    }
 
     fn try_map_order(row: &Row) -> Result<Order> {
         trace!("Try mapping row to order: {row:?}");
@@ -386,6 +2016,58 @@ impl DB {
         })
     }
 
+    fn try_map_order_item(row: &Row) -> Result<OrderItem> {
+        trace!("Try mapping row to order item: {row:?}");
+        Ok(OrderItem {
+            itemId: row.try_get_required("ItemID")?,
+            consId: row.try_get_required("ConsID")?,
+            num: row.try_get_value("Num")?,
+            catCode: row.try_get_value("CatCode")?,
+            accountGrn: row.try_get_value("AccountGrn")?,
+            accountPrice: row.try_get_value("AccountPrice")?,
+            manualFix: row.try_get_value("ManualFix")?,
+        })
+    }
+
+    fn try_map_payment(row: &Row) -> Result<Payment> {
+        trace!("Try mapping row to payment: {row:?}");
+        Ok(Payment {
+            payId: row.try_get_required("PayID")?,
+            consId: row.try_get_required("ConsID")?,
+            payDate: row.try_get_optional("PayDate")?,
+            paidGrn: row.try_get_value("PaidGrn")?,
+            payDocNum: row.try_get_string("PayDocNum")?,
+        })
+    }
+
+    fn try_map_payment_view(row: &Row) -> Result<PaymentView> {
+        trace!("Try mapping row to payment view: {row:?}");
+        Ok(PaymentView {
+            payId: row.try_get_required("PayID")?,
+            consId: row.try_get_required("ConsID")?,
+            supplierId: row.try_get_value("SellerID")?,
+            accountNum: row.try_get_string("AccountNum")?,
+            payDate: row.try_get_optional("PayDate")?,
+            paidGrn: row.try_get_value("PaidGrn")?,
+            payDocNum: row.try_get_string("PayDocNum")?,
+        })
+    }
+
+    fn try_map_request(row: &Row) -> Result<ConsRequest> {
+        trace!("Try mapping row to request: {row:?}");
+        Ok(ConsRequest {
+            reqId: row.try_get_required("ReqID")?,
+            requestState: row.try_get_value("RequestState")?,
+            requestDate: row.try_get_optional("RequestDate")?,
+            userCode: row.try_get_value("UserCode")?,
+            catCode: row.try_get_value("CatCode")?,
+            needDate: row.try_get_optional("NeedDate")?,
+            num: row.try_get_value("Num")?,
+            cancelRequest: row.try_get_value("CancelRequest")?,
+            refuseRequest: row.try_get_value("RefuseRequest")?,
+        })
+    }
+
     fn try_map_category(row: &Row) -> Result<Category> {
         trace!("Try mapping row to category: {row:?}");
         Ok(Category {
@@ -416,3 +2098,39 @@ impl DB {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::MissingEnterpriseClaim;
+
+    #[test]
+    fn orders_of_other_enterprises_are_not_found() {
+        let own = EnterpriseAccess::Only(2);
+        assert!(DB::check_visible(Some(2), own).is_ok());
+        assert!(DB::check_visible(Some(3), EnterpriseAccess::All).is_ok());
+
+        for (enterprise_id, access) in [(Some(3), own), (None, own), (None, EnterpriseAccess::All)]
+        {
+            let err = DB::check_visible(enterprise_id, access).unwrap_err();
+            assert!(err.is::<DBRecordNotFound>(), "{err:?}");
+        }
+        let err = DB::check_visible(Some(2), EnterpriseAccess::Unassigned).unwrap_err();
+        assert!(err.is::<MissingEnterpriseClaim>(), "{err:?}");
+    }
+
+    #[test]
+    fn payments_are_restricted_to_the_enterprise() {
+        let (_, all) = DB::payments_query(EnterpriseAccess::All).unwrap();
+        assert!(!all.contains(" where "), "{all}");
+
+        let (_, own) = DB::payments_query(EnterpriseAccess::Only(2)).unwrap();
+        assert!(
+            own.contains(" where cr.EnterpriseID = @P1 order by PayDate"),
+            "{own}"
+        );
+
+        let err = DB::payments_query(EnterpriseAccess::Unassigned).unwrap_err();
+        assert!(err.is::<MissingEnterpriseClaim>(), "{err:?}");
+    }
+}
@@ -5,3 +5,80 @@ pub struct DBRecordNotFound;
 #[derive(thiserror::Error, Debug, Clone, Copy)]
 #[error("Required field value not found")]
 pub struct MissingRequiredField;
+
+#[derive(thiserror::Error, Debug, Clone, Copy)]
+#[error("Category with code {0} does not exist")]
+pub struct UnknownCategoryCode(pub i32);
+
+#[derive(thiserror::Error, Debug, Clone)]
+#[error("User id '{0}' is not a valid user code")]
+pub struct InvalidUserCode(pub String);
+
+#[derive(thiserror::Error, Debug, Clone, Copy)]
+#[error("Cannot {action} a request which is {state}")]
+pub struct IllegalRequestTransition {
+    pub action: &'static str,
+    pub state: &'static str,
+}
+
+#[derive(thiserror::Error, Debug, Clone)]
+#[error("Sorting by '{field}' is not supported")]
+pub struct InvalidSortField {
+    pub field: String,
+    pub allowed: Vec<&'static str>,
+}
+
+#[derive(thiserror::Error, Debug, Clone)]
+#[error("Invalid paging: {0}")]
+pub struct InvalidPaging(pub String);
+
+#[derive(thiserror::Error, Debug, Clone, Copy)]
+#[error("Record is still referenced by {0}")]
+pub struct RecordInUse(pub &'static str);
+
+#[derive(thiserror::Error, Debug, Clone, Copy)]
+#[error("Parent category {0} does not exist")]
+pub struct UnknownParentCategory(pub i32);
+
+#[derive(thiserror::Error, Debug, Clone, Copy)]
+#[error("Category {parent_id} is the category {id} itself or one of its descendants")]
+pub struct CategoryCycle {
+    pub id: i32,
+    pub parent_id: i32,
+}
+
+#[derive(thiserror::Error, Debug, Clone, Copy)]
+#[error("If-Match header is required to modify this record")]
+pub struct PreconditionRequired;
+
+#[derive(thiserror::Error, Debug, Clone)]
+#[error("Record has been modified, its current ETag is {0}")]
+pub struct PreconditionFailed(pub String);
+
+#[derive(thiserror::Error, Debug, Clone)]
+#[error("Invalid configuration:\n  {}", .0.join("\n  "))]
+pub struct InvalidConfiguration(pub Vec<String>);
+
+#[derive(thiserror::Error, Debug, Clone)]
+#[error("Refusing to start with insecure configuration:\n  {}", .0.join("\n  "))]
+pub struct InsecureConfiguration(pub Vec<String>);
+
+#[derive(thiserror::Error, Debug, Clone, Copy)]
+#[error("Token does not grant scope {0}")]
+pub struct MissingScope(pub &'static str);
+
+#[derive(thiserror::Error, Debug, Clone, Copy)]
+#[error("Token does not have role {0}")]
+pub struct MissingRole(pub &'static str);
+
+#[derive(thiserror::Error, Debug, Clone)]
+#[error("Invalid token lifetime {0}, expected a number with unit s, m, h, d or w")]
+pub struct InvalidTtl(pub String);
+
+#[derive(thiserror::Error, Debug, Clone, Copy)]
+#[error("Token does not allow access to enterprise {0}")]
+pub struct ForeignEnterprise(pub i32);
+
+#[derive(thiserror::Error, Debug, Clone, Copy)]
+#[error("Token is not assigned to an enterprise")]
+pub struct MissingEnterpriseClaim;
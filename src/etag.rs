@@ -0,0 +1,122 @@
+use anyhow::{Result, bail};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use warp::{
+    Reply,
+    http::{HeaderValue, StatusCode, header},
+    reply::Response,
+};
+
+use crate::errors::{PreconditionFailed, PreconditionRequired};
+
+/// Strong entity tag computed from the json representation of the value
+pub fn of<T: Serialize>(value: &T) -> Result<String> {
+    let json = serde_json::to_vec(value)?;
+    let hash = Sha256::digest(&json);
+    let hex: String = hash[..16].iter().map(|b| format!("{b:02x}")).collect();
+    Ok(format!("\"{hex}\""))
+}
+
+// Entity tags listed in `If-Match`/`If-None-Match`, `*` matches any
+fn list_matches(header: &str, etag: &str, weak: bool) -> bool {
+    header.split(',').map(str::trim).any(|tag| {
+        if tag == "*" {
+            return true;
+        }
+        match tag.strip_prefix("W/") {
+            Some(weak_tag) => weak && weak_tag == etag,
+            None => tag == etag,
+        }
+    })
+}
+
+/// Value of the `If-Match` request header, required for updates and deletes
+#[derive(Debug, Clone)]
+pub struct IfMatch(Option<String>);
+
+impl IfMatch {
+    pub fn new(header: Option<String>) -> Self {
+        IfMatch(header)
+    }
+
+    pub fn check<T: Serialize>(&self, current: &T) -> Result<()> {
+        let Some(header) = &self.0 else {
+            bail!(PreconditionRequired)
+        };
+        let etag = of(current)?;
+        if !list_matches(header, &etag, false) {
+            bail!(PreconditionFailed(etag))
+        }
+        Ok(())
+    }
+}
+
+/// Json reply with `ETag` header, or empty 304 if `If-None-Match` lists the same tag
+pub fn reply<T: Serialize>(value: &T, if_none_match: Option<&str>) -> Result<Response> {
+    let etag = of(value)?;
+
+    let mut response = match if_none_match {
+        Some(header) if list_matches(header, &etag, true) => {
+            warp::reply::with_status(warp::reply(), StatusCode::NOT_MODIFIED).into_response()
+        }
+        _ => warp::reply::json(value).into_response(),
+    };
+    response
+        .headers_mut()
+        .insert(header::ETAG, HeaderValue::from_str(&etag)?);
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize)]
+    struct Item {
+        id: i32,
+        name: &'static str,
+    }
+
+    #[test]
+    fn etag_changes_with_content() {
+        let first = of(&Item { id: 1, name: "a" }).unwrap();
+        let same = of(&Item { id: 1, name: "a" }).unwrap();
+        let other = of(&Item { id: 1, name: "b" }).unwrap();
+
+        assert_eq!(first, same);
+        assert_ne!(first, other);
+        assert!(first.starts_with('"') && first.ends_with('"'));
+    }
+
+    #[test]
+    fn if_match_uses_strong_comparison() {
+        let item = Item { id: 1, name: "a" };
+        let etag = of(&item).unwrap();
+
+        assert!(IfMatch::new(Some(etag.clone())).check(&item).is_ok());
+        assert!(
+            IfMatch::new(Some(format!("\"x\", {etag}")))
+                .check(&item)
+                .is_ok()
+        );
+        assert!(IfMatch::new(Some("*".to_string())).check(&item).is_ok());
+
+        let weak = IfMatch::new(Some(format!("W/{etag}"))).check(&item);
+        assert!(weak.unwrap_err().is::<PreconditionFailed>());
+        let missing = IfMatch::new(None).check(&item);
+        assert!(missing.unwrap_err().is::<PreconditionRequired>());
+    }
+
+    #[test]
+    fn if_none_match_returns_not_modified() {
+        let item = Item { id: 1, name: "a" };
+        let etag = of(&item).unwrap();
+
+        let response = reply(&item, Some(&format!("W/{etag}"))).unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()[header::ETAG], etag.as_str());
+
+        let response = reply(&item, Some("\"other\"")).unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
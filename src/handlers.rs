@@ -1,71 +1,481 @@
 use crate::{
+    DBPool,
+    audit::{Audit, Route},
+    auth,
+    connection_manager::TiberiusConnection,
     db::DB,
-    model::{CreateCategory, CreateOrder, CreateSupplier, User, ViewFilter},
+    errors::InvalidUserCode,
+    etag::{self, IfMatch},
+    health, metrics,
+    model::{
+        AuditFilter, CreateCategory, CreateOrder, CreateOrderItem, CreatePayment, CreateRequest,
+        CreateSupplier, CreateToken, DeleteParams, Paging, PatchOrder, RequestFilter,
+        SupplierFilter, UpdateCategory, UpdateOrder, UpdateSupplier, User, ViewFilter,
+    },
+    pagination::{PageLinks, PageRequest, page_reply},
+    revocation,
     url_part_utf8_string::UrlPartUtf8String,
 };
 use anyhow::Result;
+use tracing::instrument;
 use warp::{self, Rejection, Reply, http::StatusCode, reply};
 
-pub async fn list_orders(_: User, db: DB) -> Result<impl Reply, Rejection> {
-    map_result(db.get_orders().await.map(|orders| reply::json(&orders)))
+#[instrument(skip_all)]
+pub async fn list_orders(
+    paging: Paging,
+    links: PageLinks,
+    user: User,
+    db: DB,
+) -> Result<impl Reply, Rejection> {
+    let page = PageRequest::from_paging(&paging).map_err(reject)?;
+    map_result(
+        db.get_orders(page, user.enterprise)
+            .await
+            .map(|orders| page_reply(orders, &links)),
+    )
 }
 
+#[instrument(skip_all)]
 pub async fn list_orders_filtered(
     filter: ViewFilter,
-    _: User,
+    paging: Paging,
+    links: PageLinks,
+    user: User,
     db: DB,
 ) -> Result<impl Reply, Rejection> {
+    let page = PageRequest::from_paging(&paging).map_err(reject)?;
     map_result(
-        db.get_orders_filtered(filter)
+        db.get_orders_filtered(filter, page, user.enterprise)
             .await
-            .map(|orders| reply::json(&orders)),
+            .map(|orders| page_reply(orders, &links)),
     )
 }
 
-pub async fn get_order(id: i32, _: User, db: DB) -> Result<impl Reply, Rejection> {
-    map_result(db.get_order(id).await.map(|order| reply::json(&order)))
+#[instrument(skip_all)]
+pub async fn get_order(
+    id: i32,
+    if_none_match: Option<String>,
+    user: User,
+    db: DB,
+) -> Result<impl Reply, Rejection> {
+    map_result(
+        db.get_order(id, user.enterprise)
+            .await
+            .and_then(|order| etag::reply(&order, if_none_match.as_deref())),
+    )
 }
 
-pub async fn create_order(order: CreateOrder, _: User, db: DB) -> Result<impl Reply, Rejection> {
+#[instrument(skip_all)]
+pub async fn create_order(
+    order: CreateOrder,
+    route: Route,
+    user: User,
+    db: DB,
+) -> Result<impl Reply, Rejection> {
+    let audit = Audit::new(&user, route);
     map_result(
-        db.create_order(order)
+        db.create_order(order, user.enterprise, &audit)
             .await
             .map(|order| reply::with_status(reply::json(&order), StatusCode::CREATED)),
     )
 }
 
-pub async fn list_categories(_: User, db: DB) -> Result<impl Reply, Rejection> {
-    map_result(db.get_categories().await.map(|cats| reply::json(&cats)))
+#[instrument(skip_all)]
+pub async fn update_order(
+    id: i32,
+    order: UpdateOrder,
+    if_match: IfMatch,
+    route: Route,
+    user: User,
+    db: DB,
+) -> Result<impl Reply, Rejection> {
+    let audit = Audit::new(&user, route);
+    map_result(
+        db.update_order(id, order, &if_match, user.enterprise, &audit)
+            .await
+            .and_then(|order| etag::reply(&order, None)),
+    )
+}
+
+#[instrument(skip_all)]
+pub async fn patch_order(
+    id: i32,
+    order: PatchOrder,
+    if_match: IfMatch,
+    route: Route,
+    user: User,
+    db: DB,
+) -> Result<impl Reply, Rejection> {
+    let audit = Audit::new(&user, route);
+    map_result(
+        db.patch_order(id, order, &if_match, user.enterprise, &audit)
+            .await
+            .and_then(|order| etag::reply(&order, None)),
+    )
+}
+
+#[instrument(skip_all)]
+pub async fn delete_order(
+    id: i32,
+    params: DeleteParams,
+    if_match: IfMatch,
+    route: Route,
+    user: User,
+    db: DB,
+) -> Result<impl Reply, Rejection> {
+    let audit = Audit::new(&user, route);
+    map_result(
+        db.delete_order(id, params.cascade, &if_match, user.enterprise, &audit)
+            .await
+            .map(|()| reply::reply()),
+    )
+}
+
+#[instrument(skip_all)]
+pub async fn list_order_items(order_id: i32, user: User, db: DB) -> Result<impl Reply, Rejection> {
+    map_result(
+        db.get_order_items(order_id, user.enterprise)
+            .await
+            .map(|items| reply::json(&items)),
+    )
+}
+
+#[instrument(skip_all)]
+pub async fn get_order_item(
+    order_id: i32,
+    item_id: i32,
+    if_none_match: Option<String>,
+    user: User,
+    db: DB,
+) -> Result<impl Reply, Rejection> {
+    map_result(
+        db.get_order_item(order_id, item_id, user.enterprise)
+            .await
+            .and_then(|item| etag::reply(&item, if_none_match.as_deref())),
+    )
+}
+
+#[instrument(skip_all)]
+pub async fn create_order_item(
+    order_id: i32,
+    item: CreateOrderItem,
+    route: Route,
+    user: User,
+    db: DB,
+) -> Result<impl Reply, Rejection> {
+    let audit = Audit::new(&user, route);
+    map_result(
+        db.create_order_item(order_id, item, user.enterprise, &audit)
+            .await
+            .map(|item| reply::with_status(reply::json(&item), StatusCode::CREATED)),
+    )
+}
+
+#[instrument(skip_all)]
+pub async fn update_order_item(
+    order_id: i32,
+    item_id: i32,
+    item: CreateOrderItem,
+    if_match: IfMatch,
+    route: Route,
+    user: User,
+    db: DB,
+) -> Result<impl Reply, Rejection> {
+    let audit = Audit::new(&user, route);
+    map_result(
+        db.update_order_item(order_id, item_id, item, &if_match, user.enterprise, &audit)
+            .await
+            .and_then(|item| etag::reply(&item, None)),
+    )
+}
+
+#[instrument(skip_all)]
+pub async fn delete_order_item(
+    order_id: i32,
+    item_id: i32,
+    if_match: IfMatch,
+    route: Route,
+    user: User,
+    db: DB,
+) -> Result<impl Reply, Rejection> {
+    let audit = Audit::new(&user, route);
+    map_result(
+        db.delete_order_item(order_id, item_id, &if_match, user.enterprise, &audit)
+            .await
+            .map(|()| reply::reply()),
+    )
+}
+
+#[instrument(skip_all)]
+pub async fn list_payments(user: User, db: DB) -> Result<impl Reply, Rejection> {
+    map_result(
+        db.get_payments(user.enterprise)
+            .await
+            .map(|payments| reply::json(&payments)),
+    )
 }
 
-pub async fn get_category(id: i32, _: User, db: DB) -> Result<impl Reply, Rejection> {
-    map_result(db.get_category(id).await.map(|cat| reply::json(&cat)))
+#[instrument(skip_all)]
+pub async fn list_order_payments(
+    order_id: i32,
+    user: User,
+    db: DB,
+) -> Result<impl Reply, Rejection> {
+    map_result(
+        db.get_order_payments(order_id, user.enterprise)
+            .await
+            .map(|payments| reply::json(&payments)),
+    )
 }
 
+#[instrument(skip_all)]
+pub async fn create_payment(
+    order_id: i32,
+    payment: CreatePayment,
+    route: Route,
+    user: User,
+    db: DB,
+) -> Result<impl Reply, Rejection> {
+    let audit = Audit::new(&user, route);
+    map_result(
+        db.create_payment(order_id, payment, user.enterprise, &audit)
+            .await
+            .map(|payment| reply::with_status(reply::json(&payment), StatusCode::CREATED)),
+    )
+}
+
+#[instrument(skip_all)]
+pub async fn get_payment(
+    id: i32,
+    if_none_match: Option<String>,
+    user: User,
+    db: DB,
+) -> Result<impl Reply, Rejection> {
+    map_result(
+        db.get_payment(id, user.enterprise)
+            .await
+            .and_then(|payment| etag::reply(&payment, if_none_match.as_deref())),
+    )
+}
+
+#[instrument(skip_all)]
+pub async fn delete_payment(
+    id: i32,
+    if_match: IfMatch,
+    route: Route,
+    user: User,
+    db: DB,
+) -> Result<impl Reply, Rejection> {
+    let audit = Audit::new(&user, route);
+    map_result(
+        db.delete_payment(id, &if_match, user.enterprise, &audit)
+            .await
+            .map(|()| reply::reply()),
+    )
+}
+
+#[instrument(skip_all)]
+pub async fn list_requests(
+    filter: RequestFilter,
+    _: User,
+    db: DB,
+) -> Result<impl Reply, Rejection> {
+    map_result(
+        db.get_open_requests(filter)
+            .await
+            .map(|requests| reply::json(&requests)),
+    )
+}
+
+#[instrument(skip_all)]
+pub async fn get_request(id: i32, _: User, db: DB) -> Result<impl Reply, Rejection> {
+    map_result(
+        db.get_request(id)
+            .await
+            .map(|request| reply::json(&request)),
+    )
+}
+
+#[instrument(skip_all)]
+pub async fn create_request(
+    request: CreateRequest,
+    route: Route,
+    user: User,
+    db: DB,
+) -> Result<impl Reply, Rejection> {
+    let audit = Audit::new(&user, route);
+    let code = user_code(&user).map_err(reject)?;
+    map_result(
+        db.create_request(request, code, &audit)
+            .await
+            .map(|request| reply::with_status(reply::json(&request), StatusCode::CREATED)),
+    )
+}
+
+#[instrument(skip_all)]
+pub async fn cancel_request(
+    id: i32,
+    route: Route,
+    user: User,
+    db: DB,
+) -> Result<impl Reply, Rejection> {
+    let audit = Audit::new(&user, route);
+    let code = user_code(&user).map_err(reject)?;
+    map_result(
+        db.cancel_request(id, code, &audit)
+            .await
+            .map(|request| reply::json(&request)),
+    )
+}
+
+#[instrument(skip_all)]
+pub async fn refuse_request(
+    id: i32,
+    route: Route,
+    user: User,
+    db: DB,
+) -> Result<impl Reply, Rejection> {
+    let audit = Audit::new(&user, route);
+    let code = user_code(&user).map_err(reject)?;
+    map_result(
+        db.refuse_request(id, code, &audit)
+            .await
+            .map(|request| reply::json(&request)),
+    )
+}
+
+#[instrument(skip_all)]
+pub async fn list_categories(
+    paging: Paging,
+    links: PageLinks,
+    _: User,
+    db: DB,
+) -> Result<impl Reply, Rejection> {
+    let page = PageRequest::from_paging(&paging).map_err(reject)?;
+    map_result(
+        db.get_categories(page)
+            .await
+            .map(|cats| page_reply(cats, &links)),
+    )
+}
+
+#[instrument(skip_all)]
+pub async fn get_category(
+    id: i32,
+    if_none_match: Option<String>,
+    _: User,
+    db: DB,
+) -> Result<impl Reply, Rejection> {
+    map_result(
+        db.get_category(id)
+            .await
+            .and_then(|cat| etag::reply(&cat, if_none_match.as_deref())),
+    )
+}
+
+#[instrument(skip_all)]
 pub async fn create_category(
     cat: CreateCategory,
-    _: User,
+    route: Route,
+    user: User,
     db: DB,
 ) -> Result<impl Reply, Rejection> {
+    let audit = Audit::new(&user, route);
     map_result(
-        db.create_category(cat)
+        db.create_category(cat, &audit)
             .await
             .map(|cat| reply::with_status(reply::json(&cat), StatusCode::CREATED)),
     )
 }
 
-pub async fn delete_category(id: i32, _: User, db: DB) -> Result<impl Reply, Rejection> {
-    map_result(db.delete_category(id).await.map(|()| reply::reply()))
+#[instrument(skip_all)]
+pub async fn get_category_tree(_: User, db: DB) -> Result<impl Reply, Rejection> {
+    map_result(db.get_category_tree().await.map(|tree| reply::json(&tree)))
 }
 
-pub async fn get_supplier_by_id(id: i32, _: User, db: DB) -> Result<impl Reply, Rejection> {
+#[instrument(skip_all)]
+pub async fn get_category_descendants(id: i32, _: User, db: DB) -> Result<impl Reply, Rejection> {
+    map_result(
+        db.get_category_descendants(id)
+            .await
+            .map(|cats| reply::json(&cats)),
+    )
+}
+
+#[instrument(skip_all)]
+pub async fn get_category_ancestors(id: i32, _: User, db: DB) -> Result<impl Reply, Rejection> {
+    map_result(
+        db.get_category_ancestors(id)
+            .await
+            .map(|cats| reply::json(&cats)),
+    )
+}
+
+#[instrument(skip_all)]
+pub async fn update_category(
+    id: i32,
+    cat: UpdateCategory,
+    if_match: IfMatch,
+    route: Route,
+    user: User,
+    db: DB,
+) -> Result<impl Reply, Rejection> {
+    let audit = Audit::new(&user, route);
+    map_result(
+        db.update_category(id, cat, &if_match, &audit)
+            .await
+            .and_then(|cat| etag::reply(&cat, None)),
+    )
+}
+
+#[instrument(skip_all)]
+pub async fn delete_category(
+    id: i32,
+    params: DeleteParams,
+    if_match: IfMatch,
+    route: Route,
+    user: User,
+    db: DB,
+) -> Result<impl Reply, Rejection> {
+    let audit = Audit::new(&user, route);
+    map_result(
+        db.delete_category(id, params.cascade, &if_match, &audit)
+            .await
+            .map(|()| reply::reply()),
+    )
+}
+
+#[instrument(skip_all)]
+pub async fn list_suppliers(
+    filter: SupplierFilter,
+    paging: Paging,
+    links: PageLinks,
+    _: User,
+    db: DB,
+) -> Result<impl Reply, Rejection> {
+    let page = PageRequest::from_paging(&paging).map_err(reject)?;
+    map_result(
+        db.get_suppliers(filter, page)
+            .await
+            .map(|suppliers| page_reply(suppliers, &links)),
+    )
+}
+
+#[instrument(skip_all)]
+pub async fn get_supplier_by_id(
+    id: i32,
+    if_none_match: Option<String>,
+    _: User,
+    db: DB,
+) -> Result<impl Reply, Rejection> {
     map_result(
         db.get_supplier_by_id(id)
             .await
-            .map(|supplier| reply::json(&supplier)),
+            .and_then(|supplier| etag::reply(&supplier, if_none_match.as_deref())),
     )
 }
 
+#[instrument(skip_all)]
 pub async fn get_supplier_by_name(
     name: UrlPartUtf8String,
     _: User,
@@ -78,20 +488,188 @@ pub async fn get_supplier_by_name(
     )
 }
 
+#[instrument(skip_all)]
 pub async fn create_supplier(
     supplier: CreateSupplier,
-    _: User,
+    route: Route,
+    user: User,
     db: DB,
 ) -> Result<impl Reply, Rejection> {
+    let audit = Audit::new(&user, route);
     map_result(
-        db.create_supplier(supplier)
+        db.create_supplier(supplier, &audit)
             .await
             .map(|supplier| reply::with_status(reply::json(&supplier), StatusCode::CREATED)),
     )
 }
 
+#[instrument(skip_all)]
+pub async fn update_supplier(
+    id: i32,
+    supplier: CreateSupplier,
+    if_match: IfMatch,
+    route: Route,
+    user: User,
+    db: DB,
+) -> Result<impl Reply, Rejection> {
+    let audit = Audit::new(&user, route);
+    map_result(
+        db.update_supplier(id, supplier, &if_match, &audit)
+            .await
+            .and_then(|supplier| etag::reply(&supplier, None)),
+    )
+}
+
+#[instrument(skip_all)]
+pub async fn patch_supplier(
+    id: i32,
+    supplier: UpdateSupplier,
+    if_match: IfMatch,
+    route: Route,
+    user: User,
+    db: DB,
+) -> Result<impl Reply, Rejection> {
+    let audit = Audit::new(&user, route);
+    map_result(
+        db.patch_supplier(id, supplier, &if_match, &audit)
+            .await
+            .and_then(|supplier| etag::reply(&supplier, None)),
+    )
+}
+
+#[instrument(skip_all)]
+pub async fn delete_supplier(
+    id: i32,
+    if_match: IfMatch,
+    route: Route,
+    user: User,
+    db: DB,
+) -> Result<impl Reply, Rejection> {
+    let audit = Audit::new(&user, route);
+    map_result(
+        db.delete_supplier(id, &if_match, &audit)
+            .await
+            .map(|()| reply::reply()),
+    )
+}
+
+#[instrument(skip_all)]
+pub async fn issue_token(
+    request: CreateToken,
+    route: Route,
+    user: User,
+    db: DB,
+) -> Result<impl Reply, Rejection> {
+    let audit = Audit::new(&user, route);
+    info!(target: "service", "User {} issues token for {} with scopes {:?} and roles {:?}", user.id, request.sub, request.scopes, request.roles);
+    // The token is not returned unless its audit record is written
+    let issued = async {
+        let token = auth::issue_token(auth::keys(), &request)?;
+        db.record_issued_token(&token.jti, &request, &audit).await?;
+        Ok(token)
+    };
+    map_result(
+        issued
+            .await
+            .map(|token| reply::with_status(reply::json(&token), StatusCode::CREATED)),
+    )
+}
+
+#[instrument(skip_all)]
+pub async fn revoke_token(
+    jti: String,
+    route: Route,
+    user: User,
+    db: DB,
+) -> Result<impl Reply, Rejection> {
+    let audit = Audit::new(&user, route);
+    info!(target: "service", "User {} revokes token {}", user.id, jti);
+    map_result(db.revoke_token(&jti, &audit).await.map(|()| {
+        revocation::insert(&jti);
+        reply::reply()
+    }))
+}
+
+// Requests keep the numeric code of the user, which is the token subject
+// Stored in the cancel and refuse flags of requests, where 0 means not set
+fn user_code(user: &User) -> anyhow::Result<i32> {
+    match user.id.parse() {
+        Ok(code) if code > 0 => Ok(code),
+        _ => Err(InvalidUserCode(user.id.clone()).into()),
+    }
+}
+
 fn map_result(result: anyhow::Result<impl Reply>) -> Result<impl Reply, Rejection> {
-    result
-        .map_err(crate::problem::from_anyhow)
-        .map_err(warp::reject::custom)
+    result.map_err(reject)
+}
+
+fn reject(e: anyhow::Error) -> Rejection {
+    warp::reject::custom(crate::problem::from_anyhow(e))
+}
+
+#[instrument(skip_all)]
+pub async fn live() -> Result<impl Reply, Rejection> {
+    Ok(reply::json(&serde_json::json!({ "live": true })))
+}
+
+// 503 lets the load balancer take the instance out of rotation
+#[instrument(skip_all)]
+pub async fn ready(db: DBPool, manager: TiberiusConnection) -> Result<impl Reply, Rejection> {
+    let readiness = health::readiness(&db, &manager).await;
+    let status = if readiness.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    Ok(reply::with_status(reply::json(&readiness), status))
+}
+
+#[instrument(skip_all)]
+pub async fn version() -> Result<impl Reply, Rejection> {
+    Ok(reply::json(&health::version()))
+}
+
+#[instrument(skip_all)]
+pub async fn metrics(_: User, db: DB) -> Result<impl Reply, Rejection> {
+    map_result(
+        metrics::render(&db.pool_state())
+            .map(|text| reply::with_header(text, "content-type", "text/plain; version=0.0.4")),
+    )
+}
+
+#[instrument(skip_all)]
+pub async fn list_audit(
+    filter: AuditFilter,
+    paging: Paging,
+    links: PageLinks,
+    _: User,
+    db: DB,
+) -> Result<impl Reply, Rejection> {
+    let page = PageRequest::from_paging(&paging).map_err(reject)?;
+    map_result(
+        db.get_audit(filter, page)
+            .await
+            .map(|entries| page_reply(entries, &links)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::EnterpriseAccess;
+
+    fn user(id: &str) -> User {
+        User {
+            id: id.to_string(),
+            enterprise: EnterpriseAccess::All,
+        }
+    }
+
+    #[test]
+    fn user_code_must_be_positive() {
+        assert_eq!(user_code(&user("12")).unwrap(), 12);
+        assert!(user_code(&user("0")).is_err());
+        assert!(user_code(&user("-3")).is_err());
+        assert!(user_code(&user("alice")).is_err());
+    }
 }
@@ -0,0 +1,54 @@
+use std::time::Duration;
+
+use bb8::ManageConnection;
+use tokio::time::timeout;
+
+use crate::{
+    DBPool, configuration,
+    connection_manager::TiberiusConnection,
+    model::{PoolState, Readiness, Version},
+};
+
+// Readiness probe fails rather than waiting for the pool connection timeout
+const READY_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Whether a pooled connection to SQL Server answers within the timeout
+pub async fn readiness(pool: &DBPool, manager: &TiberiusConnection) -> Readiness {
+    let check = async {
+        let mut conn = pool.get().await.map_err(|e| e.to_string())?;
+        manager.is_valid(&mut conn).await.map_err(|e| e.to_string())
+    };
+    let database = match timeout(READY_TIMEOUT, check).await {
+        Ok(Ok(())) => None,
+        Ok(Err(e)) => {
+            warn!(target: "service", "Readiness check failed: {e}");
+            Some("unreachable".to_string())
+        }
+        Err(_) => {
+            warn!(target: "service", "Readiness check timed out after {READY_TIMEOUT:?}");
+            Some("timed out".to_string())
+        }
+    };
+
+    let state = pool.state();
+    Readiness {
+        ready: database.is_none(),
+        database: database.unwrap_or_else(|| "ok".to_string()),
+        pool: PoolState {
+            connections: state.connections,
+            idleConnections: state.idle_connections,
+            maxSize: configuration::get().max_pool(),
+            pendingGets: state.statistics.pending_gets(),
+            timedOutGets: state.statistics.get_timed_out,
+        },
+    }
+}
+
+/// Set by build script
+pub fn version() -> Version {
+    Version {
+        version: env!("CARGO_PKG_VERSION"),
+        commit: env!("CONSUM_GIT_COMMIT"),
+        commitTime: env!("CONSUM_COMMIT_TIME"),
+    }
+}
@@ -0,0 +1,297 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    thread::{self, JoinHandle},
+};
+
+use chrono::{DateTime, DurationRound, NaiveDateTime, TimeDelta, Utc};
+use flate2::{Compression, write::GzEncoder};
+
+use crate::configuration::LogRotation;
+
+const TIMESTAMP_FORMAT: &str = "%Y%m%dT%H%M%S";
+
+/// When the log file is rotated and how many rotated files are kept
+#[derive(Debug, Clone, PartialEq)]
+pub struct RotationPolicy {
+    pub period: LogRotation,
+    pub max_size: Option<u64>,
+    pub max_files: usize,
+    pub compress: bool,
+}
+
+/// Log file renamed to `<name>.<timestamp>.<ext>` when its period ends or it reaches the size limit.
+/// The timestamp is the start of the period the file holds, or the time of rotation without periods.
+/// Compression and removal of old files run in a background thread.
+pub struct RotatingFile {
+    path: PathBuf,
+    policy: RotationPolicy,
+    file: File,
+    size: u64,
+    period: Option<DateTime<Utc>>,
+    cleanup: Option<JoinHandle<()>>,
+}
+
+impl RotatingFile {
+    pub fn open(path: impl Into<PathBuf>, policy: RotationPolicy) -> io::Result<Self> {
+        let path = path.into();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let metadata = file.metadata()?;
+        // A file left by the previous run belongs to the period it was last written in
+        let modified = metadata
+            .modified()
+            .map(DateTime::<Utc>::from)
+            .unwrap_or_else(|_| Utc::now());
+        Ok(RotatingFile {
+            period: period_of(policy.period, modified),
+            path,
+            policy,
+            file,
+            size: metadata.len(),
+            cleanup: None,
+        })
+    }
+
+    fn rotate_if_needed(&mut self, now: DateTime<Utc>, incoming: usize) -> io::Result<()> {
+        let period = period_of(self.policy.period, now);
+        let period_ended = period != self.period;
+        let too_big = self
+            .policy
+            .max_size
+            .is_some_and(|max| self.size > 0 && self.size + incoming as u64 > max);
+
+        if self.size > 0 && (period_ended || too_big) {
+            // A file of a day or an hour is found by the name of that day or hour
+            self.rotate(self.period.unwrap_or(now))?;
+        }
+        self.period = period;
+        Ok(())
+    }
+
+    fn rotate(&mut self, timestamp: DateTime<Utc>) -> io::Result<()> {
+        self.file.flush()?;
+        // The previous cleanup would race with this one over the same files
+        if let Some(cleanup) = self.cleanup.take() {
+            let _ = cleanup.join();
+        }
+
+        let rotated = self.rotated_name(timestamp);
+        fs::rename(&self.path, &rotated)?;
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+
+        let path = self.path.clone();
+        let policy = self.policy.clone();
+        self.cleanup = Some(thread::spawn(move || {
+            if policy.compress
+                && let Err(e) = compress(&rotated)
+            {
+                eprintln!("Cannot compress log file {}: {e}", rotated.display());
+            }
+            if let Err(e) = remove_old_files(&path, policy.max_files) {
+                eprintln!("Cannot remove old log files: {e}");
+            }
+        }));
+        Ok(())
+    }
+
+    fn rotated_name(&self, timestamp: DateTime<Utc>) -> PathBuf {
+        let (stem, extension) = split_name(&self.path);
+        let timestamp = timestamp.format(TIMESTAMP_FORMAT);
+        // Several rotations with the same timestamp, when the size limit is reached within a period
+        (0..)
+            .map(|n| {
+                let suffix = if n == 0 {
+                    String::new()
+                } else {
+                    format!("-{n}")
+                };
+                self.path
+                    .with_file_name(format!("{stem}.{timestamp}{suffix}{extension}"))
+            })
+            .find(|path| !path.exists() && !gz_name(path).exists())
+            .expect("unbounded range")
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Logging goes on in the current file when it cannot be rotated
+        if let Err(e) = self.rotate_if_needed(Utc::now(), buf.len()) {
+            eprintln!("Cannot rotate log file {}: {e}", self.path.display());
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+// Start of the period the time belongs to
+fn period_of(rotation: LogRotation, time: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    let length = match rotation {
+        LogRotation::Never => return None,
+        LogRotation::Hourly => TimeDelta::hours(1),
+        LogRotation::Daily => TimeDelta::days(1),
+    };
+    time.duration_trunc(length).ok()
+}
+
+// `output.log` is split into `output` and `.log`
+fn split_name(path: &Path) -> (String, String) {
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let extension = path
+        .extension()
+        .map(|ext| format!(".{}", ext.to_string_lossy()))
+        .unwrap_or_default();
+    (stem, extension)
+}
+
+fn gz_name(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".gz");
+    PathBuf::from(name)
+}
+
+fn compress(path: &Path) -> io::Result<()> {
+    let mut encoder = GzEncoder::new(File::create(gz_name(path))?, Compression::default());
+    io::copy(&mut File::open(path)?, &mut encoder)?;
+    encoder.finish()?;
+    fs::remove_file(path)
+}
+
+fn remove_old_files(path: &Path, max_files: usize) -> io::Result<()> {
+    let (stem, extension) = split_name(path);
+    let prefix = format!("{stem}.");
+    let compressed = format!("{extension}.gz");
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+
+    // Sorted by timestamp, then by the number added when rotated twice within a second
+    let mut rotated: Vec<((String, u32), PathBuf)> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|file| file != path)
+        .filter_map(|file| {
+            let name = file.file_name()?.to_string_lossy().to_string();
+            let rest = name.strip_prefix(&prefix)?;
+            let rest = rest
+                .strip_suffix(&compressed)
+                .or_else(|| rest.strip_suffix(&extension))?;
+            let (timestamp, n) = match rest.split_once('-') {
+                Some((timestamp, n)) => (timestamp, n.parse().ok()?),
+                None => (rest, 0),
+            };
+            // Other files sharing the prefix, like `output.backup.log`, are left alone
+            NaiveDateTime::parse_from_str(timestamp, TIMESTAMP_FORMAT).ok()?;
+            Some(((timestamp.to_string(), n), file))
+        })
+        .collect();
+    rotated.sort();
+
+    let excess = rotated.len().saturating_sub(max_files);
+    for (_, old) in &rotated[..excess] {
+        fs::remove_file(old)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use uuid::Uuid;
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("consum-log-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn file_names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        names
+    }
+
+    fn wait_cleanup(file: &mut RotatingFile) {
+        if let Some(cleanup) = file.cleanup.take() {
+            cleanup.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn rotates_by_size_and_keeps_max_files() {
+        let dir = temp_dir();
+        fs::write(dir.join("output.backup.log"), "backup\n").unwrap();
+        let policy = RotationPolicy {
+            period: LogRotation::Never,
+            max_size: Some(10),
+            max_files: 2,
+            compress: false,
+        };
+        let mut file = RotatingFile::open(dir.join("output.log"), policy).unwrap();
+        for line in ["first 01\n", "second 2\n", "third 03\n", "fourth 4\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+            wait_cleanup(&mut file);
+        }
+
+        let names = file_names(&dir);
+        assert_eq!(names.len(), 4, "{names:?}");
+        assert!(names.contains(&"output.log".to_string()));
+        assert!(names.contains(&"output.backup.log".to_string()));
+        assert_eq!(
+            fs::read_to_string(dir.join("output.log")).unwrap(),
+            "fourth 4\n"
+        );
+        let kept: Vec<String> = names
+            .iter()
+            .filter(|name| *name != "output.log" && *name != "output.backup.log")
+            .map(|name| fs::read_to_string(dir.join(name)).unwrap())
+            .collect();
+        assert_eq!(kept.len(), 2);
+        assert!(!kept.contains(&"first 01\n".to_string()), "{kept:?}");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rotates_daily_and_compresses() {
+        let dir = temp_dir();
+        let policy = RotationPolicy {
+            period: LogRotation::Daily,
+            max_size: None,
+            max_files: 5,
+            compress: true,
+        };
+        let mut file = RotatingFile::open(dir.join("output.log"), policy).unwrap();
+        let day = Utc.with_ymd_and_hms(2026, 3, 1, 23, 59, 0).unwrap();
+        file.write_all(b"yesterday\n").unwrap();
+        // Written on the last minute of the day
+        file.period = period_of(LogRotation::Daily, day);
+
+        file.rotate_if_needed(day + chrono::Duration::minutes(1), 6)
+            .unwrap();
+        file.write_all(b"today\n").unwrap();
+        wait_cleanup(&mut file);
+
+        assert_eq!(
+            file_names(&dir),
+            ["output.20260301T000000.log.gz", "output.log"]
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,16 +1,36 @@
+mod audit;
 mod auth;
+mod category_tree;
+mod cli;
 mod configuration;
 mod connection_manager;
 mod db;
 mod errors;
+mod etag;
 mod handlers;
+mod health;
 mod http_compat;
+mod log_file;
+mod metrics;
 mod model;
+mod pagination;
 mod problem;
+mod query_builder;
+mod request_id;
+mod revocation;
+mod safety;
+mod sql_error;
 mod startup;
+mod telemetry;
 mod url_part_utf8_string;
 
+use std::process::ExitCode;
+
+use clap::Parser;
+use cli::{Cli, Command, TokenCommand};
+use configuration::Configuration;
 use connection_manager::TiberiusConnection;
+use model::CreateToken;
 
 type DBPool = bb8::Pool<TiberiusConnection>;
 
@@ -20,12 +40,80 @@ mod windows_service_main;
 #[macro_use]
 extern crate log;
 
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    // Logger is not set up yet, problems go to stderr
+    let mut config = match Configuration::load(cli.config.as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    if cli.insecure_dev {
+        config.set_insecure_dev(true);
+    }
+    if cli.print_config {
+        print!("{}", config.redacted());
+        return ExitCode::SUCCESS;
+    }
+    let keys = match auth::Keys::load(&config) {
+        Ok(keys) => keys,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    if let Some(Command::Token(TokenCommand::Issue {
+        sub,
+        ttl,
+        scopes,
+        roles,
+        enterprise,
+    })) = cli.command
+    {
+        let request = CreateToken {
+            sub,
+            ttl,
+            scopes,
+            roles,
+            enterprise,
+        };
+        return match auth::issue_token(&keys, &request) {
+            Ok(issued) => {
+                println!("{}", issued.token);
+                eprintln!("Token id {}, expires at {}", issued.jti, issued.expiresAt);
+                ExitCode::SUCCESS
+            }
+            Err(e) => {
+                eprintln!("{e}");
+                ExitCode::FAILURE
+            }
+        };
+    }
+    configuration::init(config);
+    auth::init_keys(keys);
+
+    run()
+}
+
 #[cfg(feature = "run-windows-service")]
-fn main() -> windows_service::Result<()> {
-    windows_service_main::run()
+fn run() -> ExitCode {
+    match windows_service_main::run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::FAILURE
+        }
+    }
 }
 
 #[cfg(not(feature = "run-windows-service"))]
-fn main() {
-    startup::run();
+fn run() -> ExitCode {
+    match startup::run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(_) => ExitCode::FAILURE,
+    }
 }
@@ -0,0 +1,204 @@
+use std::{future::Future, sync::LazyLock, time::Instant};
+
+use tracing::{Instrument, Span, field::Empty};
+
+use prometheus::{
+    Encoder, HistogramVec, IntCounterVec, IntGauge, TextEncoder, register_histogram,
+    register_histogram_vec, register_int_counter_vec, register_int_gauge,
+};
+
+const HTTP_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+// Longest route, deeper paths are not routes of the API
+const MAX_ROUTE_SEGMENTS: usize = 4;
+
+// Path segments kept in the `route` label, other segments are ids or names
+const ROUTE_SEGMENTS: &[&str] = &[
+    "orders",
+    "views",
+    "items",
+    "payments",
+    "requests",
+    "cancel",
+    "refuse",
+    "categories",
+    "tree",
+    "descendants",
+    "ancestors",
+    "suppliers",
+    "name",
+    "auth",
+    "tokens",
+    "audit",
+    "health",
+    "live",
+    "ready",
+    "version",
+    "metrics",
+];
+
+static HTTP_REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "http_requests_total",
+        "HTTP requests by route and status",
+        &["method", "route", "status"]
+    )
+    .unwrap()
+});
+
+static HTTP_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "http_request_duration_seconds",
+        "HTTP request latency",
+        &["method", "route"],
+        HTTP_BUCKETS.to_vec()
+    )
+    .unwrap()
+});
+
+static SQL_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "db_query_duration_seconds",
+        "Duration of DB methods, including waiting for a connection",
+        &["method"],
+        HTTP_BUCKETS.to_vec()
+    )
+    .unwrap()
+});
+
+static SQL_ERRORS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "db_query_errors_total",
+        "DB methods which returned an error, including not found records",
+        &["method"]
+    )
+    .unwrap()
+});
+
+static POOL_ACQUIRE: LazyLock<prometheus::Histogram> = LazyLock::new(|| {
+    register_histogram!(
+        "db_pool_acquire_seconds",
+        "Time to get a connection from the pool",
+        HTTP_BUCKETS.to_vec()
+    )
+    .unwrap()
+});
+
+static POOL_CONNECTIONS: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!("db_pool_connections", "Connections managed by the pool").unwrap()
+});
+
+static POOL_IDLE: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!("db_pool_idle_connections", "Idle connections in the pool").unwrap()
+});
+
+static POOL_WAITERS: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!("db_pool_waiters", "Requests waiting for a connection").unwrap()
+});
+
+/// Counts the finished request, called by the access log filter after rejections are recovered
+pub fn observe_request(info: warp::log::Info) {
+    let method = info.method().as_str();
+    let route = route_label(info.path());
+    HTTP_REQUESTS
+        .with_label_values(&[method, &route, info.status().as_str()])
+        .inc();
+    HTTP_DURATION
+        .with_label_values(&[method, &route])
+        .observe(info.elapsed().as_secs_f64());
+}
+
+/// Times the DB method, counts its errors and traces it in a span.
+/// The method records the rows it returns and the wait for a pooled connection in the span.
+pub async fn sql<T>(
+    method: &str,
+    query: impl Future<Output = anyhow::Result<T>>,
+) -> anyhow::Result<T> {
+    let span = tracing::info_span!(
+        "sql",
+        otel.name = method,
+        otel.kind = "client",
+        db.system.name = "mssql",
+        db.operation.name = method,
+        db.rows = Empty,
+        db.pool_wait_ms = Empty,
+        otel.status_code = Empty,
+    );
+    let start = Instant::now();
+    let result = query.instrument(span.clone()).await;
+    SQL_DURATION
+        .with_label_values(&[method])
+        .observe(start.elapsed().as_secs_f64());
+    if result.is_err() {
+        SQL_ERRORS.with_label_values(&[method]).inc();
+        span.record("otel.status_code", "error");
+    }
+    result
+}
+
+/// Called inside the span of the DB method
+pub fn observe_acquire(start: Instant) {
+    let elapsed = start.elapsed();
+    POOL_ACQUIRE.observe(elapsed.as_secs_f64());
+    Span::current().record("db.pool_wait_ms", elapsed.as_secs_f64() * 1000.0);
+}
+
+/// Called inside the span of the DB method
+pub fn record_rows(rows: usize) {
+    Span::current().record("db.rows", rows);
+}
+
+/// Metrics in Prometheus text format, with pool gauges taken at the time of the scrape
+pub fn render(pool: &bb8::State) -> anyhow::Result<String> {
+    POOL_CONNECTIONS.set(pool.connections.into());
+    POOL_IDLE.set(pool.idle_connections.into());
+    POOL_WAITERS.set(pool.statistics.pending_gets() as i64);
+
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&prometheus::gather(), &mut buffer)?;
+    Ok(String::from_utf8(buffer)?)
+}
+
+/// Path with ids and names replaced, so the label has one value per route
+pub fn route_label(path: &str) -> String {
+    let segments: Vec<&str> = path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(|segment| {
+            if ROUTE_SEGMENTS.contains(&segment) {
+                segment
+            } else if segment.parse::<i64>().is_ok() {
+                "{id}"
+            } else {
+                "{name}"
+            }
+        })
+        .collect();
+
+    match segments.first() {
+        Some(first) if ROUTE_SEGMENTS.contains(first) && segments.len() <= MAX_ROUTE_SEGMENTS => {
+            format!("/{}", segments.join("/"))
+        }
+        Some(_) => "other".to_string(),
+        None => "/".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn route_label_hides_ids() {
+        assert_eq!(route_label("/orders/12/items/3"), "/orders/{id}/items/{id}");
+        assert_eq!(
+            route_label("/suppliers/name/Acme%20Ltd"),
+            "/suppliers/name/{name}"
+        );
+        assert_eq!(route_label("/categories/tree"), "/categories/tree");
+        assert_eq!(route_label("/wp-admin/setup.php"), "other");
+        assert_eq!(route_label("/orders/1/2/3/4/5"), "other");
+    }
+}
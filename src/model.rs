@@ -1,5 +1,10 @@
-use serde::{Deserialize, Serialize};
-use tiberius::{numeric::Decimal, time::chrono::NaiveDateTime};
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::auth::EnterpriseAccess;
+use tiberius::{
+    numeric::Decimal,
+    time::chrono::{DateTime, NaiveDateTime, Utc},
+};
 
 #[allow(non_snake_case)]
 #[derive(Debug, Serialize)]
@@ -36,10 +41,99 @@ pub struct OrderView {
     pub accountGrn: Decimal,
 }
 
+#[allow(non_snake_case)]
+#[derive(Debug, Serialize)]
+pub struct OrderItem {
+    pub itemId: i32,
+    pub consId: i32,
+    pub num: Decimal,
+    pub catCode: i32,
+    pub accountGrn: Decimal,
+    pub accountPrice: Decimal,
+    pub manualFix: bool,
+}
+
+#[allow(non_snake_case)]
+#[derive(Debug, Serialize)]
+pub struct Payment {
+    pub payId: i32,
+    pub consId: i32,
+    pub payDate: Option<NaiveDateTime>,
+    pub paidGrn: Decimal,
+    pub payDocNum: Option<String>,
+}
+
+#[allow(non_snake_case)]
+#[derive(Debug, Serialize)]
+pub struct PaymentView {
+    pub payId: i32,
+    pub consId: i32,
+    pub supplierId: i32,
+    pub accountNum: Option<String>,
+    pub payDate: Option<NaiveDateTime>,
+    pub paidGrn: Decimal,
+    pub payDocNum: Option<String>,
+}
+
+#[allow(non_snake_case)]
+#[derive(Debug, Serialize)]
+pub struct CreatedPayment {
+    pub payment: Payment,
+    pub paidGrn: Decimal, // total paid for the order, including this payment
+}
+
+#[allow(non_snake_case)]
+#[derive(Debug, Serialize)]
+pub struct ConsRequest {
+    pub reqId: i32,
+    pub requestState: i32,
+    pub requestDate: Option<NaiveDateTime>,
+    pub userCode: i32,
+    pub catCode: i32,
+    pub needDate: Option<NaiveDateTime>,
+    pub num: Decimal,
+    pub cancelRequest: i32, // code of the user who cancelled the request, 0 if not cancelled
+    pub refuseRequest: i32, // code of the user who refused the request, 0 if not refused
+}
+
+#[allow(non_snake_case)]
+#[derive(Debug, Deserialize)]
+pub struct RequestFilter {
+    pub userCode: Option<i32>,
+    pub catCode: Option<i32>,
+    pub needDateFrom: Option<NaiveDateTime>,
+    pub needDateTo: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortDirection {
+    #[default]
+    Asc,
+    Desc,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SortOrder {
+    pub field: String,
+    #[serde(default)]
+    pub direction: SortDirection,
+}
+
 #[allow(non_snake_case)]
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ViewFilter {
-    pub orderBy: Option<String>,
+    #[serde(default)]
+    pub orderBy: Vec<SortOrder>,
+    pub incomeDateFrom: Option<NaiveDateTime>,
+    pub incomeDateTo: Option<NaiveDateTime>,
+    pub accountDateFrom: Option<NaiveDateTime>,
+    pub accountDateTo: Option<NaiveDateTime>,
+    pub supplierId: Option<i32>,
+    pub enterpriseId: Option<i32>,
+    pub hasTrust: Option<bool>,
+    #[serde(default)]
+    pub unpaidOnly: bool,
 }
 
 #[allow(non_snake_case)]
@@ -52,6 +146,13 @@ pub struct Category {
     pub code: i32,
 }
 
+#[derive(Debug, Serialize)]
+pub struct CategoryNode {
+    #[serde(flatten)]
+    pub category: Category,
+    pub children: Vec<CategoryNode>,
+}
+
 #[allow(non_snake_case)]
 #[derive(Debug, Serialize)]
 pub struct Supplier {
@@ -86,6 +187,79 @@ pub struct CreateOrder {
     pub enterpriseId: i32,
 }
 
+#[allow(non_snake_case)]
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UpdateOrder {
+    pub orderState: i32,
+    pub accountNum: String,
+    pub accountDate: NaiveDateTime,
+    pub incomeDate: NaiveDateTime,
+    pub hasTrust: bool,
+    pub trustSer: Option<String>,
+    pub trustNum: Option<i32>,
+    pub supplierId: i32,
+    pub bySelf: Option<i32>,
+    pub comment: String,
+    pub enterpriseId: i32,
+}
+
+// PATCH body: a missing field keeps the column, `null` clears the columns PUT allows to be null
+#[allow(non_snake_case)]
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PatchOrder {
+    pub orderState: Option<i32>,
+    pub accountNum: Option<String>,
+    pub accountDate: Option<NaiveDateTime>,
+    pub incomeDate: Option<NaiveDateTime>,
+    pub hasTrust: Option<bool>,
+    #[serde(default, deserialize_with = "deserialize_some")]
+    pub trustSer: Option<Option<String>>,
+    #[serde(default, deserialize_with = "deserialize_some")]
+    pub trustNum: Option<Option<i32>>,
+    pub supplierId: Option<i32>,
+    #[serde(default, deserialize_with = "deserialize_some")]
+    pub bySelf: Option<Option<i32>>,
+    pub comment: Option<String>,
+    pub enterpriseId: Option<i32>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct DeleteParams {
+    #[serde(default)]
+    pub cascade: bool,
+}
+
+#[allow(non_snake_case)]
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CreateOrderItem {
+    pub num: Decimal,
+    pub catCode: i32,
+    pub accountGrn: Decimal,
+    pub accountPrice: Decimal,
+    pub manualFix: bool,
+}
+
+#[allow(non_snake_case)]
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CreatePayment {
+    pub payDate: NaiveDateTime,
+    pub paidGrn: Decimal,
+    pub payDocNum: Option<String>,
+}
+
+#[allow(non_snake_case)]
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CreateRequest {
+    pub catCode: i32,
+    pub needDate: Option<NaiveDateTime>,
+    pub num: Decimal,
+}
+
 #[allow(non_snake_case)]
 #[derive(Debug, Deserialize)]
 #[serde(deny_unknown_fields)]
@@ -104,6 +278,42 @@ pub struct CreateSupplier {
     pub supplierFullName: Option<String>,
 }
 
+// PATCH body: a missing field keeps the column, `null` clears it
+#[allow(non_snake_case)]
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UpdateSupplier {
+    #[serde(default, deserialize_with = "deserialize_some")]
+    pub supplierName: Option<Option<String>>,
+    #[serde(default, deserialize_with = "deserialize_some")]
+    pub supplierPhone: Option<Option<String>>,
+    #[serde(default, deserialize_with = "deserialize_some")]
+    pub supplierFax: Option<Option<String>>,
+    #[serde(default, deserialize_with = "deserialize_some")]
+    pub supplierManager: Option<Option<String>>,
+    #[serde(default, deserialize_with = "deserialize_some")]
+    pub supplierEmail: Option<Option<String>>,
+    #[serde(default, deserialize_with = "deserialize_some")]
+    pub supplierAddressDoc: Option<Option<String>>,
+    #[serde(default, deserialize_with = "deserialize_some")]
+    pub supplierAddressFact: Option<Option<String>>,
+    #[serde(default, deserialize_with = "deserialize_some")]
+    pub supplierAddressStore: Option<Option<String>>,
+    #[serde(default, deserialize_with = "deserialize_some")]
+    pub supplierStoreTime: Option<Option<String>>,
+    #[serde(default, deserialize_with = "deserialize_some")]
+    pub supplierStoreWho: Option<Option<String>>,
+    #[serde(default, deserialize_with = "deserialize_some")]
+    pub supplierStorePhone: Option<Option<String>>,
+    #[serde(default, deserialize_with = "deserialize_some")]
+    pub supplierFullName: Option<Option<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SupplierFilter {
+    pub q: Option<String>,
+}
+
 #[allow(non_snake_case)]
 #[derive(Debug, Deserialize)]
 #[serde(deny_unknown_fields)]
@@ -114,12 +324,107 @@ pub struct CreateCategory {
     pub code: i32,
 }
 
+// PATCH body: a missing field keeps the column, `null` parentId moves the category to the root
+#[allow(non_snake_case)]
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UpdateCategory {
+    #[serde(default, deserialize_with = "deserialize_some")]
+    pub parentId: Option<Option<i32>>,
+    pub catName: Option<String>,
+    pub catUnitCode: Option<i32>,
+    pub code: Option<i32>,
+}
+
+#[allow(non_snake_case)]
+#[derive(Debug, Serialize)]
+pub struct PoolState {
+    pub connections: u32,
+    pub idleConnections: u32,
+    pub maxSize: u32,
+    pub pendingGets: u64,
+    pub timedOutGets: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub database: String,
+    pub pool: PoolState,
+}
+
+#[allow(non_snake_case)]
+#[derive(Debug, Serialize)]
+pub struct Version {
+    pub version: &'static str,
+    pub commit: &'static str,
+    pub commitTime: &'static str,
+}
+
 #[derive(Debug, Deserialize)]
+pub struct AuditFilter {
+    pub entity: Option<String>,
+    pub id: Option<String>,
+}
+
+// Change recorded in the audit trail, `before` is missing for created and `after` for deleted records
+#[allow(non_snake_case)]
+#[derive(Debug, Serialize)]
+pub struct AuditEntry {
+    pub auditId: i32,
+    pub auditDate: NaiveDateTime,
+    pub userId: String,
+    pub route: String,
+    pub entity: String,
+    pub entityId: String,
+    pub before: Option<serde_json::Value>,
+    pub after: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Paging {
+    pub limit: Option<u32>,
+    pub offset: Option<u32>,
+    pub after: Option<i32>,
+}
+
+#[derive(Debug, Default, Deserialize)]
 pub struct ApiKey {
-    pub api_key: String,
+    pub api_key: Option<String>,
 }
 
 #[derive(Debug)]
 pub struct User {
     pub id: String,
+    pub enterprise: EnterpriseAccess,
+}
+
+// Serialized into the audit record of the issued token
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct CreateToken {
+    pub sub: String,
+    pub ttl: String,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    #[serde(default)]
+    pub roles: Vec<String>,
+    pub enterprise: Option<i32>,
+}
+
+#[allow(non_snake_case)]
+#[derive(Debug, Serialize)]
+pub struct IssuedToken {
+    pub token: String,
+    pub jti: String,
+    pub expiresAt: DateTime<Utc>,
+}
+
+// Distinguishes a field set to `null` (Some(None)) from a missing one (None)
+fn deserialize_some<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    T::deserialize(deserializer).map(Some)
 }
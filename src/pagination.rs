@@ -0,0 +1,248 @@
+use anyhow::{Result, bail};
+use serde::Serialize;
+use warp::{Reply, http::HeaderValue, reply::Response};
+
+use crate::{errors::InvalidPaging, model::Paging, query_builder::QueryBuilder};
+
+const DEFAULT_LIMIT: u32 = 100;
+const MAX_LIMIT: u32 = 1000;
+
+/// Validated paging parameters
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PageRequest {
+    pub limit: u32,
+    pub offset: u32,
+    pub after: Option<i32>,
+}
+
+impl PageRequest {
+    pub fn from_paging(paging: &Paging) -> Result<Self> {
+        let limit = paging.limit.unwrap_or(DEFAULT_LIMIT);
+        if limit == 0 || limit > MAX_LIMIT {
+            bail!(InvalidPaging(format!(
+                "limit must be between 1 and {MAX_LIMIT}"
+            )))
+        }
+        // Bound to an `int` parameter of OFFSET
+        if paging.offset.is_some_and(|offset| offset > i32::MAX as u32) {
+            bail!(InvalidPaging(format!(
+                "offset must not exceed {}",
+                i32::MAX
+            )))
+        }
+        if paging.after.is_some() && paging.offset.is_some() {
+            bail!(InvalidPaging(
+                "after and offset cannot be used together".to_string()
+            ))
+        }
+
+        Ok(PageRequest {
+            limit,
+            offset: paging.offset.unwrap_or_default(),
+            after: paging.after,
+        })
+    }
+
+    /// Restricts the query to the rows after the keyset position, if any
+    pub fn keyset(&self, builder: &mut QueryBuilder, key_column: &str) {
+        builder.optional(key_column, ">", self.after);
+    }
+
+    /// Renders ` offset ... fetch next ...` part, must follow `order by`
+    pub fn fetch_clause(&self, builder: &mut QueryBuilder) -> String {
+        let offset = builder.param(self.offset as i32);
+        let limit = builder.param(self.limit as i32);
+        format!(" offset {offset} rows fetch next {limit} rows only")
+    }
+}
+
+/// One page of a list together with the data needed to link to the neighbour pages
+#[derive(Debug)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub total: i32,
+    request: PageRequest,
+    last_key: Option<i32>,
+}
+
+impl<T> Page<T> {
+    pub fn new(items: Vec<T>, total: i32, request: PageRequest, key: impl Fn(&T) -> i32) -> Self {
+        let last_key = items.last().map(key);
+        Page {
+            items,
+            total,
+            request,
+            last_key,
+        }
+    }
+
+    fn next(&self) -> Option<Vec<(&'static str, String)>> {
+        let request = &self.request;
+        if request.after.is_some() {
+            if self.items.len() < request.limit as usize {
+                return None;
+            }
+            return self.last_key.map(|key| {
+                vec![
+                    ("limit", request.limit.to_string()),
+                    ("after", key.to_string()),
+                ]
+            });
+        }
+
+        let next_offset = request.offset as i64 + request.limit as i64;
+        if next_offset >= self.total as i64 {
+            return None;
+        }
+        Some(vec![
+            ("limit", request.limit.to_string()),
+            ("offset", next_offset.to_string()),
+        ])
+    }
+
+    fn prev(&self) -> Option<Vec<(&'static str, String)>> {
+        let request = &self.request;
+        if request.after.is_some() || request.offset == 0 {
+            return None;
+        }
+        Some(vec![
+            ("limit", request.limit.to_string()),
+            (
+                "offset",
+                request.offset.saturating_sub(request.limit).to_string(),
+            ),
+        ])
+    }
+}
+
+/// Path and query of the current request, used to build `Link` header values
+#[derive(Debug, Clone)]
+pub struct PageLinks {
+    path: String,
+    query: String,
+}
+
+impl PageLinks {
+    pub fn new(path: &str, query: String) -> Self {
+        PageLinks {
+            path: path.to_string(),
+            query,
+        }
+    }
+
+    // Keeps all query parameters except the paging ones, which are replaced,
+    // and the API key, which must not be echoed into headers cached by proxies
+    fn link(&self, paging: &[(&str, String)], rel: &str) -> String {
+        let mut params: Vec<String> = self
+            .query
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .filter(|pair| {
+                let key = pair.split('=').next().unwrap_or_default();
+                !matches!(key, "limit" | "offset" | "after" | "api_key")
+            })
+            .map(ToString::to_string)
+            .collect();
+        params.extend(paging.iter().map(|(key, value)| format!("{key}={value}")));
+
+        format!("<{}?{}>; rel=\"{rel}\"", self.path, params.join("&"))
+    }
+
+    fn header<T>(&self, page: &Page<T>) -> Option<String> {
+        let links: Vec<String> = [
+            page.next().map(|next| self.link(&next, "next")),
+            page.prev().map(|prev| self.link(&prev, "prev")),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+
+        if links.is_empty() {
+            return None;
+        }
+        Some(links.join(", "))
+    }
+}
+
+/// Serializes page items as json array, with `X-Total-Count` and `Link` headers
+pub fn page_reply<T: Serialize>(page: Page<T>, links: &PageLinks) -> Response {
+    let mut response = warp::reply::json(&page.items).into_response();
+    let headers = response.headers_mut();
+    headers.insert("x-total-count", HeaderValue::from(page.total));
+    if let Some(link) = links.header(&page)
+        && let Ok(value) = HeaderValue::from_str(&link)
+    {
+        headers.insert("link", value);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(limit: u32, offset: u32, after: Option<i32>) -> PageRequest {
+        PageRequest {
+            limit,
+            offset,
+            after,
+        }
+    }
+
+    #[test]
+    fn offset_page_links_keep_other_parameters() {
+        let page = Page::new(vec![11, 12], 30, request(2, 10, None), |v| *v);
+        let links = PageLinks::new(
+            "/suppliers",
+            "q=abc&api_key=secret&limit=2&offset=10".to_string(),
+        );
+
+        assert_eq!(
+            links.header(&page).unwrap(),
+            "</suppliers?q=abc&limit=2&offset=12>; rel=\"next\", \
+            </suppliers?q=abc&limit=2&offset=8>; rel=\"prev\""
+        );
+    }
+
+    #[test]
+    fn last_offset_page_has_no_next_link() {
+        let page = Page::new(vec![1, 2], 4, request(2, 2, None), |v| *v);
+        let links = PageLinks::new("/orders", String::new());
+
+        assert_eq!(
+            links.header(&page).unwrap(),
+            "</orders?limit=2&offset=0>; rel=\"prev\""
+        );
+    }
+
+    #[test]
+    fn keyset_page_links_to_last_key() {
+        let page = Page::new(vec![5, 7, 9], 100, request(3, 0, Some(4)), |v| *v);
+        let links = PageLinks::new("/orders", "after=4".to_string());
+
+        assert_eq!(
+            links.header(&page).unwrap(),
+            "</orders?limit=3&after=9>; rel=\"next\""
+        );
+    }
+
+    #[test]
+    fn offset_must_fit_sql_int() {
+        let paging = Paging {
+            limit: None,
+            offset: Some(i32::MAX as u32 + 1),
+            after: None,
+        };
+        assert!(PageRequest::from_paging(&paging).is_err());
+    }
+
+    #[test]
+    fn after_and_offset_are_exclusive() {
+        let paging = Paging {
+            limit: None,
+            offset: Some(10),
+            after: Some(5),
+        };
+        assert!(PageRequest::from_paging(&paging).is_err());
+    }
+}
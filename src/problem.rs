@@ -1,7 +1,19 @@
-use crate::errors::DBRecordNotFound;
+use crate::errors::{
+    CategoryCycle, DBRecordNotFound, ForeignEnterprise, IllegalRequestTransition, InvalidPaging,
+    InvalidSortField, InvalidTtl, InvalidUserCode, MissingEnterpriseClaim, MissingRole,
+    MissingScope, PreconditionFailed, PreconditionRequired, RecordInUse, UnknownCategoryCode,
+    UnknownParentCategory,
+};
+use crate::{request_id, sql_error};
 use http::StatusCode;
 use http_api_problem::HttpApiProblem;
-use warp::{self, Rejection, Reply, reject::InvalidQuery};
+use warp::{
+    self, Rejection, Reply,
+    reject::{InvalidQuery, MethodNotAllowed, UnsupportedMediaType},
+};
+
+// Challenge sent with 401 responses
+const WWW_AUTHENTICATE: &str = "Bearer realm=\"consum-api\"";
 
 pub fn from_anyhow(e: anyhow::Error) -> HttpApiProblem {
     let e = match e.downcast::<HttpApiProblem>() {
@@ -9,43 +21,292 @@ pub fn from_anyhow(e: anyhow::Error) -> HttpApiProblem {
         Err(e) => e,
     };
 
-    error!("Error processing request:\n{e:?}");
+    let problem = classify(&e);
+    // Details stay in the log, the client gets the request id in `instance` to quote
+    if problem.status.is_none_or(|status| status.is_server_error()) {
+        error!("Error processing request:\n{e:?}");
+    } else {
+        info!("Request refused: {e:#}");
+    }
+    problem
+}
 
+fn classify(e: &anyhow::Error) -> HttpApiProblem {
+    if let Some(problem) = sql_error::classify(e) {
+        return problem;
+    }
     if e.is::<DBRecordNotFound>() {
         return HttpApiProblem::new(StatusCode::NOT_FOUND).title("Record not found");
     }
+    if let Some(err) = e.downcast_ref::<UnknownCategoryCode>() {
+        return HttpApiProblem::new(StatusCode::UNPROCESSABLE_ENTITY)
+            .title(err.to_string())
+            .value("catCode", &err.0);
+    }
+    if let Some(err) = e.downcast_ref::<UnknownParentCategory>() {
+        return HttpApiProblem::new(StatusCode::UNPROCESSABLE_ENTITY)
+            .title(err.to_string())
+            .value("parentId", &err.0);
+    }
+    if let Some(err) = e.downcast_ref::<CategoryCycle>() {
+        return HttpApiProblem::new(StatusCode::UNPROCESSABLE_ENTITY)
+            .title(err.to_string())
+            .value("parentId", &err.parent_id);
+    }
+    if let Some(err) = e.downcast_ref::<IllegalRequestTransition>() {
+        return HttpApiProblem::new(StatusCode::CONFLICT)
+            .title(err.to_string())
+            .value("action", &err.action)
+            .value("state", &err.state);
+    }
+    if let Some(err) = e.downcast_ref::<PreconditionRequired>() {
+        return HttpApiProblem::new(StatusCode::PRECONDITION_REQUIRED).title(err.to_string());
+    }
+    if let Some(err) = e.downcast_ref::<PreconditionFailed>() {
+        return HttpApiProblem::new(StatusCode::PRECONDITION_FAILED)
+            .title("Record has been modified")
+            .value("etag", &err.0);
+    }
+    if let Some(err) = e.downcast_ref::<RecordInUse>() {
+        return HttpApiProblem::new(StatusCode::CONFLICT)
+            .title(err.to_string())
+            .value("referencedBy", &err.0);
+    }
+    if let Some(err) = e.downcast_ref::<InvalidSortField>() {
+        return HttpApiProblem::new(StatusCode::BAD_REQUEST)
+            .title(err.to_string())
+            .value("field", &err.field)
+            .value("allowed", &err.allowed);
+    }
+    if let Some(err) = e.downcast_ref::<InvalidPaging>() {
+        return HttpApiProblem::new(StatusCode::BAD_REQUEST).title(err.to_string());
+    }
+    if let Some(err) = e.downcast_ref::<InvalidTtl>() {
+        return HttpApiProblem::new(StatusCode::BAD_REQUEST)
+            .title(err.to_string())
+            .value("ttl", &err.0);
+    }
+    if let Some(err) = e.downcast_ref::<MissingScope>() {
+        return HttpApiProblem::new(StatusCode::FORBIDDEN)
+            .title(err.to_string())
+            .value("scope", &err.0);
+    }
+    if let Some(err) = e.downcast_ref::<MissingRole>() {
+        return HttpApiProblem::new(StatusCode::FORBIDDEN)
+            .title(err.to_string())
+            .value("role", &err.0);
+    }
+    if let Some(err) = e.downcast_ref::<ForeignEnterprise>() {
+        return HttpApiProblem::new(StatusCode::FORBIDDEN)
+            .title(err.to_string())
+            .value("enterpriseId", &err.0);
+    }
+    if let Some(err) = e.downcast_ref::<MissingEnterpriseClaim>() {
+        return HttpApiProblem::new(StatusCode::FORBIDDEN).title(err.to_string());
+    }
+    if let Some(err) = e.downcast_ref::<InvalidUserCode>() {
+        return HttpApiProblem::new(StatusCode::FORBIDDEN).title(err.to_string());
+    }
     HttpApiProblem::new(StatusCode::INTERNAL_SERVER_ERROR)
-        .title(format!("Internal Server Error\n{e:#}"))
+        .type_url("/problems/internal-error")
+        .title("Internal Server Error")
+}
+
+/// Problem for a body which is not valid JSON or does not match the expected type
+pub fn from_json_error(err: serde_path_to_error::Error<serde_json::Error>) -> HttpApiProblem {
+    let path = err.path().to_string();
+    let err = err.into_inner();
+    let message = err.to_string();
+    // The position is given in separate members
+    let message = message
+        .strip_suffix(&format!(" at line {} column {}", err.line(), err.column()))
+        .unwrap_or(&message);
+
+    let mut problem = HttpApiProblem::new(StatusCode::BAD_REQUEST)
+        .type_url("/problems/invalid-body")
+        .title("Invalid JSON body")
+        .detail(message);
+    if err.is_data() {
+        if let Some(field) = field_path(&path, message) {
+            problem.set_value("field", &field);
+        }
+        if let Some((_, expected)) = message.split_once(", expected ") {
+            problem.set_value("expected", &expected);
+        }
+    }
+    problem
+        .value("line", &err.line())
+        .value("column", &err.column())
+}
+
+// Path of the field in error, `.` being the whole body.
+// Missing fields are not in the path yet, their name is only in the message.
+fn field_path(path: &str, message: &str) -> Option<String> {
+    let missing = message
+        .strip_prefix("missing field `")
+        .and_then(|rest| rest.split_once('`'))
+        .map(|(name, _)| name);
+    match (path, missing) {
+        (".", Some(name)) => Some(name.to_string()),
+        (".", None) => None,
+        (path, Some(name)) => Some(format!("{path}.{name}")),
+        (path, None) => Some(path.to_string()),
+    }
+}
+
+/// Anything but JSON is refused
+pub fn is_json(content_type: &str) -> bool {
+    let media_type = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    media_type == "application/json" || media_type.ends_with("+json")
+}
+
+pub fn payload_too_large() -> HttpApiProblem {
+    HttpApiProblem::new(StatusCode::PAYLOAD_TOO_LARGE)
+        .type_url("/problems/payload-too-large")
+        .title("Request body is too large")
+}
+
+pub fn unreadable_body() -> HttpApiProblem {
+    HttpApiProblem::new(StatusCode::BAD_REQUEST)
+        .type_url("/problems/unreadable-body")
+        .title("Request body cannot be read")
+}
+
+pub fn unsupported_media_type() -> HttpApiProblem {
+    HttpApiProblem::new(StatusCode::UNSUPPORTED_MEDIA_TYPE)
+        .type_url("/problems/unsupported-media-type")
+        .title("Body must be JSON")
+        .value("expected", &"application/json")
+}
+
+pub fn invalid_path_segment(segment: &str) -> HttpApiProblem {
+    HttpApiProblem::new(StatusCode::BAD_REQUEST)
+        .type_url("/problems/invalid-path")
+        .title("Path segment is not valid percent-encoded UTF-8")
+        .value("segment", &segment)
 }
 
 pub async fn unpack(rejection: Rejection) -> Result<impl Reply, Rejection> {
     if rejection.find::<InvalidQuery>().is_some() {
-        let problem = &HttpApiProblem::new(StatusCode::BAD_REQUEST).title("Invalid query string");
+        let problem = HttpApiProblem::new(StatusCode::BAD_REQUEST).title("Invalid query string");
         let reply = get_reply(problem);
         return Ok(reply.into_response());
     }
 
     if let Some(problem) = rejection.find::<HttpApiProblem>() {
-        let reply = get_reply(problem);
+        let reply = get_reply(problem.clone());
         return Ok(reply.into_response());
     }
 
-    Err(rejection)
+    // Rejections of warp filters, the most specific first as warp combines them over routes
+    let problem = if rejection.find::<UnsupportedMediaType>().is_some() {
+        unsupported_media_type()
+    } else if rejection.find::<MethodNotAllowed>().is_some() {
+        HttpApiProblem::new(StatusCode::METHOD_NOT_ALLOWED)
+            .type_url("/problems/method-not-allowed")
+            .title("Method is not allowed for the route")
+    } else if rejection.is_not_found() {
+        HttpApiProblem::new(StatusCode::NOT_FOUND)
+            .type_url("/problems/not-found")
+            .title("Route not found")
+    } else {
+        return Err(rejection);
+    };
+    Ok(get_reply(problem).into_response())
 }
 
-fn get_reply(problem: &HttpApiProblem) -> impl Reply {
+// The request id in `instance` lets the client quote the failed request
+fn get_reply(mut problem: HttpApiProblem) -> impl Reply {
     use crate::http_compat::{header_to_warp, status_to_warp};
 
     let code = problem.status.unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    if problem.instance.is_none() {
+        problem.instance = request_id::current();
+    }
 
-    let reply = warp::reply::json(problem);
+    let reply = warp::reply::json(&problem);
     let warp_status = status_to_warp(code);
     let reply = warp::reply::with_status(reply, warp_status);
 
     let content_type = header_to_warp(&http::header::CONTENT_TYPE);
-    warp::reply::with_header(
+    let mut response = warp::reply::with_header(
         reply,
         content_type,
         http_api_problem::PROBLEM_JSON_MEDIA_TYPE,
     )
+    .into_response();
+
+    if let Some(seconds) = problem.get_value::<&str, u64>("retryAfter") {
+        response.headers_mut().insert(
+            header_to_warp(&http::header::RETRY_AFTER),
+            warp::http::HeaderValue::from(seconds),
+        );
+    }
+    if code == StatusCode::UNAUTHORIZED {
+        response.headers_mut().insert(
+            header_to_warp(&http::header::WWW_AUTHENTICATE),
+            warp::http::HeaderValue::from_static(WWW_AUTHENTICATE),
+        );
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[allow(non_snake_case, dead_code)]
+    #[derive(Debug, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct Item {
+        catCode: i32,
+        price: f64,
+    }
+
+    #[allow(dead_code)]
+    #[derive(Debug, Deserialize)]
+    struct Body {
+        items: Vec<Item>,
+    }
+
+    fn body_problem(json: &str) -> HttpApiProblem {
+        let deserializer = &mut serde_json::Deserializer::from_str(json);
+        from_json_error(serde_path_to_error::deserialize::<_, Body>(deserializer).unwrap_err())
+    }
+
+    fn field(problem: &HttpApiProblem, key: &str) -> Option<String> {
+        problem.get_value::<&str, String>(key)
+    }
+
+    #[test]
+    fn body_errors_point_at_the_field() {
+        let problem = body_problem(r#"{"items":[{"catCode":1,"price":"free"}]}"#);
+        assert_eq!(problem.status, Some(StatusCode::BAD_REQUEST));
+        assert_eq!(field(&problem, "field").as_deref(), Some("items[0].price"));
+        assert_eq!(field(&problem, "expected").as_deref(), Some("f64"));
+
+        let problem = body_problem(r#"{"items":[{"catCode":1}]}"#);
+        assert_eq!(field(&problem, "field").as_deref(), Some("items[0].price"));
+
+        let problem = body_problem(r#"{"items":[{"catCode":1,"price":2,"note":""}]}"#);
+        assert_eq!(field(&problem, "field").as_deref(), Some("items[0].note"));
+
+        let problem = body_problem(r#"{"items":"#);
+        assert_eq!(field(&problem, "field"), None);
+        assert_eq!(problem.get_value::<&str, usize>("column"), Some(9));
+    }
+
+    #[test]
+    fn accepts_json_media_types() {
+        assert!(is_json("application/json"));
+        assert!(is_json("Application/JSON; charset=utf-8"));
+        assert!(is_json("application/merge-patch+json"));
+        assert!(!is_json("text/plain"));
+    }
 }
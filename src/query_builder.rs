@@ -0,0 +1,252 @@
+use anyhow::{Result, bail};
+use tiberius::{Query, time::chrono::NaiveDateTime};
+
+use crate::{
+    errors::InvalidSortField,
+    model::{SortDirection, SortOrder},
+};
+
+/// Value bound to a `@Pn` parameter of a dynamically built query
+#[derive(Debug, Clone, PartialEq)]
+pub enum SqlParam {
+    Int(Option<i32>),
+    Bool(bool),
+    DateTime(Option<NaiveDateTime>),
+    Text(Option<String>),
+}
+
+impl From<i32> for SqlParam {
+    fn from(value: i32) -> Self {
+        SqlParam::Int(Some(value))
+    }
+}
+
+impl From<Option<i32>> for SqlParam {
+    fn from(value: Option<i32>) -> Self {
+        SqlParam::Int(value)
+    }
+}
+
+impl From<bool> for SqlParam {
+    fn from(value: bool) -> Self {
+        SqlParam::Bool(value)
+    }
+}
+
+impl From<NaiveDateTime> for SqlParam {
+    fn from(value: NaiveDateTime) -> Self {
+        SqlParam::DateTime(Some(value))
+    }
+}
+
+impl From<Option<NaiveDateTime>> for SqlParam {
+    fn from(value: Option<NaiveDateTime>) -> Self {
+        SqlParam::DateTime(value)
+    }
+}
+
+impl From<String> for SqlParam {
+    fn from(value: String) -> Self {
+        SqlParam::Text(Some(value))
+    }
+}
+
+impl From<Option<String>> for SqlParam {
+    fn from(value: Option<String>) -> Self {
+        SqlParam::Text(value)
+    }
+}
+
+/// Collects `set` assignments and `where` conditions together with their parameters,
+/// so that no user supplied value is ever concatenated into SQL text.
+#[derive(Debug, Default)]
+pub struct QueryBuilder {
+    assignments: Vec<String>,
+    conditions: Vec<String>,
+    params: Vec<SqlParam>,
+}
+
+impl QueryBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a parameter and returns its placeholder name
+    pub fn param(&mut self, value: impl Into<SqlParam>) -> String {
+        self.params.push(value.into());
+        format!("@P{}", self.params.len())
+    }
+
+    /// Adds `column = @Pn` assignment for an update statement
+    pub fn set(&mut self, column: &str, value: impl Into<SqlParam>) {
+        let param = self.param(value);
+        self.assignments.push(format!("{column} = {param}"));
+    }
+
+    /// Adds `column = @Pn` assignment if the value is present
+    pub fn set_optional<T: Into<SqlParam>>(&mut self, column: &str, value: Option<T>) {
+        if let Some(value) = value {
+            self.set(column, value);
+        }
+    }
+
+    pub fn has_assignments(&self) -> bool {
+        !self.assignments.is_empty()
+    }
+
+    pub fn set_clause(&self) -> String {
+        format!(" set {}", self.assignments.join(", "))
+    }
+
+    /// Adds `column op @Pn` condition
+    pub fn condition(&mut self, column: &str, op: &str, value: impl Into<SqlParam>) {
+        let param = self.param(value);
+        self.conditions.push(format!("{column} {op} {param}"));
+    }
+
+    /// Adds `column op @Pn` condition if the value is present
+    pub fn optional<T: Into<SqlParam>>(&mut self, column: &str, op: &str, value: Option<T>) {
+        if let Some(value) = value {
+            self.condition(column, op, value);
+        }
+    }
+
+    /// Adds a condition which does not depend on user input
+    pub fn raw_condition(&mut self, condition: &str) {
+        self.conditions.push(condition.to_string());
+    }
+
+    pub fn where_clause(&self) -> String {
+        if self.conditions.is_empty() {
+            return String::new();
+        }
+        format!(" where {}", self.conditions.join(" and "))
+    }
+
+    pub fn build(&self, sql: impl Into<String>) -> Query<'static> {
+        let mut query = Query::new(sql.into());
+        for param in &self.params {
+            match param.clone() {
+                SqlParam::Int(v) => query.bind(v),
+                SqlParam::Bool(v) => query.bind(v),
+                SqlParam::DateTime(v) => query.bind(v),
+                SqlParam::Text(v) => query.bind(v),
+            }
+        }
+        query
+    }
+}
+
+/// Turns user input into a `like` pattern matching any part of the value,
+/// wildcards in the input are escaped with `\`
+pub fn contains_pattern(value: &str) -> String {
+    let mut pattern = String::with_capacity(value.len() + 2);
+    pattern.push('%');
+    for c in value.chars() {
+        if matches!(c, '%' | '_' | '[' | '\\') {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern.push('%');
+    pattern
+}
+
+/// Renders ` order by ...` for the requested fields, allowing only the listed ones.
+/// `fields` maps public (json) field names to SQL columns.
+pub fn order_by_clause(sort: &[SortOrder], fields: &[(&'static str, &str)]) -> Result<String> {
+    let mut columns = Vec::with_capacity(sort.len());
+    for order in sort {
+        let Some((_, column)) = fields.iter().find(|(name, _)| *name == order.field) else {
+            bail!(InvalidSortField {
+                field: order.field.clone(),
+                allowed: fields.iter().map(|(name, _)| *name).collect(),
+            })
+        };
+        let direction = match order.direction {
+            SortDirection::Asc => "asc",
+            SortDirection::Desc => "desc",
+        };
+        columns.push(format!("{column} {direction}"));
+    }
+
+    if columns.is_empty() {
+        return Ok(String::new());
+    }
+    Ok(format!(" order by {}", columns.join(", ")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIELDS: &[(&str, &str)] = &[("consId", "ConsID"), ("paidGrn", "PaidGrn")];
+
+    #[test]
+    fn conditions_are_parameterized() {
+        let mut builder = QueryBuilder::new();
+        builder.condition("SellerID", "=", 5);
+        builder.optional::<bool>("HasTrust", "=", None);
+        builder.raw_condition("PaidGrn < AccountGrn");
+        builder.optional(
+            "Comment",
+            "like",
+            Some("x'; drop table ConsOrders; --".to_string()),
+        );
+
+        assert_eq!(
+            builder.where_clause(),
+            " where SellerID = @P1 and PaidGrn < AccountGrn and Comment like @P2"
+        );
+        assert_eq!(builder.params.len(), 2);
+    }
+
+    #[test]
+    fn update_assignments_precede_conditions() {
+        let mut builder = QueryBuilder::new();
+        builder.set("SellerName", "Acme".to_string());
+        builder.set_optional::<String>("SellerFax", None);
+        builder.set("SellerPhone", None::<String>);
+        builder.condition("SellerID", "=", 3);
+
+        assert_eq!(
+            builder.set_clause(),
+            " set SellerName = @P1, SellerPhone = @P2"
+        );
+        assert_eq!(builder.where_clause(), " where SellerID = @P3");
+    }
+
+    #[test]
+    fn contains_pattern_escapes_wildcards() {
+        assert_eq!(contains_pattern("50%_[a]\\"), "%50\\%\\_\\[a]\\\\%");
+    }
+
+    #[test]
+    fn order_by_uses_whitelisted_columns() {
+        let sort = vec![
+            SortOrder {
+                field: "paidGrn".to_string(),
+                direction: SortDirection::Desc,
+            },
+            SortOrder {
+                field: "consId".to_string(),
+                direction: SortDirection::Asc,
+            },
+        ];
+
+        let clause = order_by_clause(&sort, FIELDS).unwrap();
+        assert_eq!(clause, " order by PaidGrn desc, ConsID asc");
+    }
+
+    #[test]
+    fn order_by_rejects_unknown_field() {
+        let sort = vec![SortOrder {
+            field: "ConsID; drop table ConsOrders".to_string(),
+            direction: SortDirection::Asc,
+        }];
+
+        let err = order_by_clause(&sort, FIELDS).unwrap_err();
+        let err = err.downcast_ref::<InvalidSortField>().unwrap();
+        assert_eq!(err.allowed, vec!["consId", "paidGrn"]);
+    }
+}
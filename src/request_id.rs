@@ -0,0 +1,153 @@
+use tracing::{
+    Span, Subscriber,
+    field::{Empty, Field, Visit},
+    span::{Attributes, Id},
+};
+use tracing_subscriber::{
+    Registry,
+    layer::{Context, Layer},
+    registry::LookupSpan,
+};
+use uuid::Uuid;
+use warp::{Reply, http::HeaderValue, reply::Response};
+
+use crate::metrics;
+
+pub const HEADER: &str = "x-request-id";
+
+// Longer or unusual ids sent by clients are replaced, as the id ends up in logs and headers
+const MAX_LEN: usize = 128;
+
+// Field of the request span holding the id
+const FIELD: &str = "request_id";
+
+struct RequestId(String);
+
+/// Span wrapping the whole handling of a request, so every log line written meanwhile has its id.
+/// The id comes from `X-Request-Id` header or is generated.
+/// Error level keeps the span enabled whatever level `RUST_LOG` sets.
+/// The user id and response status are recorded later, when known.
+pub fn span(info: warp::trace::Info<'_>) -> Span {
+    let id = info
+        .request_headers()
+        .get(HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| is_valid(id))
+        .map(str::to_owned)
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+
+    let route = metrics::route_label(info.path());
+    tracing::error_span!(
+        target: "api",
+        "request",
+        otel.name = %format!("{} {route}", info.method()),
+        otel.kind = "server",
+        request_id = %id,
+        method = %info.method(),
+        path = %info.path(),
+        route = %route,
+        user_id = Empty,
+        status = Empty,
+        otel.status_code = Empty,
+    )
+}
+
+/// Id of the request being handled, `None` outside of a request span
+pub fn current() -> Option<String> {
+    let id = Span::current().id()?;
+    tracing::dispatcher::get_default(|dispatch| {
+        let registry = dispatch.downcast_ref::<Registry>()?;
+        let span = registry.span(&id)?;
+        span.scope()
+            .find_map(|span| span.extensions().get::<RequestId>().map(|id| id.0.clone()))
+    })
+}
+
+/// Records the user of the request, once the token is verified
+pub fn record_user(user_id: &str) {
+    Span::current().record("user_id", user_id);
+}
+
+/// Echoes the request id in the response and records the status in the request span
+pub fn with_header(reply: impl Reply) -> Response {
+    let mut response = reply.into_response();
+    let span = Span::current();
+    span.record("status", response.status().as_u16());
+    if response.status().is_server_error() {
+        span.record("otel.status_code", "error");
+    }
+    if let Some(value) = current().and_then(|id| HeaderValue::from_str(&id).ok()) {
+        response.headers_mut().insert(HEADER, value);
+    }
+    response
+}
+
+fn is_valid(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_LEN
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// Keeps the id of request spans, so `current` can return it
+pub struct RequestIdLayer;
+
+impl<S> Layer<S> for RequestIdLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut visitor = RequestIdVisitor(None);
+        attrs.record(&mut visitor);
+        if let Some(request_id) = visitor.0
+            && let Some(span) = ctx.span(id)
+        {
+            span.extensions_mut().insert(RequestId(request_id));
+        }
+    }
+}
+
+struct RequestIdVisitor(Option<String>);
+
+impl Visit for RequestIdVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == FIELD {
+            self.0 = Some(value.to_owned());
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == FIELD {
+            self.0 = Some(format!("{value:?}"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn current_id_inside_span() {
+        let subscriber = tracing_subscriber::registry().with(RequestIdLayer);
+        tracing::subscriber::with_default(subscriber, || {
+            assert_eq!(current(), None);
+
+            let span = tracing::error_span!("request", request_id = %"abc-1");
+            let _entered = span.enter();
+            let inner = tracing::info_span!("inner");
+            let _inner = inner.enter();
+            assert_eq!(current().as_deref(), Some("abc-1"));
+        });
+    }
+
+    #[test]
+    fn rejects_unusual_ids() {
+        assert!(is_valid("3f1c-0a_b.7"));
+        assert!(!is_valid(""));
+        assert!(!is_valid("id with spaces"));
+        assert!(!is_valid(&"a".repeat(MAX_LEN + 1)));
+    }
+}
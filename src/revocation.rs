@@ -0,0 +1,51 @@
+use std::{
+    collections::HashSet,
+    sync::{LazyLock, RwLock},
+    time::Duration,
+};
+
+use crate::db::DB;
+
+// `jti` of revoked tokens, loaded from `ApiRevokedToken` table
+static REVOKED: LazyLock<RwLock<HashSet<String>>> = LazyLock::new(Default::default);
+
+pub fn is_revoked(jti: &str) -> bool {
+    REVOKED.read().unwrap().contains(jti)
+}
+
+/// Revokes the token on this instance immediately, other instances pick it up on refresh
+pub fn insert(jti: &str) {
+    REVOKED.write().unwrap().insert(jti.to_owned());
+}
+
+fn replace(revoked: HashSet<String>) {
+    *REVOKED.write().unwrap() = revoked;
+}
+
+/// Reloads the revoked tokens from the database every `interval`.
+/// When the database is not available the last loaded list stays in use.
+pub async fn refresh(db: DB, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        match db.get_revoked_tokens().await {
+            Ok(revoked) => replace(revoked.into_iter().collect()),
+            Err(e) => warn!(target: "service", "Cannot refresh revoked tokens: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refresh_replaces_revoked() {
+        insert("a");
+        assert!(is_revoked("a"));
+
+        replace(HashSet::from(["b".to_string()]));
+        assert!(!is_revoked("a"));
+        assert!(is_revoked("b"));
+    }
+}
@@ -0,0 +1,60 @@
+use crate::configuration::{Configuration, DEFAULT_CONNECTION_STRING, DEFAULT_JWT_SECRET};
+
+const MIN_JWT_SECRET_LEN: usize = 32;
+
+/// Settings which are acceptable for local development only
+pub fn check(config: &Configuration) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    if config.jwt_secret() == DEFAULT_JWT_SECRET {
+        problems.push("JWT secret is the compiled-in default, set CONSUM_JWT_SECRET".to_string());
+    } else if config.jwt_secret().len() < MIN_JWT_SECRET_LEN {
+        problems.push(format!(
+            "JWT secret is shorter than {MIN_JWT_SECRET_LEN} bytes"
+        ));
+    }
+
+    if config.connection_string() == DEFAULT_CONNECTION_STRING {
+        problems.push(
+            "Connection string is the compiled-in default, set CONSUM_CONNECTION_STRING"
+                .to_string(),
+        );
+    }
+
+    if !config.addr().ip().is_loopback() && !config.tls_proxy() {
+        problems.push(format!(
+            "Binding non-loopback address {} without TLS, put a TLS terminating proxy \
+            in front of the service and set CONSUM_TLS_PROXY=true",
+            config.addr()
+        ));
+    }
+
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(file: &str) -> Configuration {
+        Configuration::from_sources(Some(("consum.toml", file)), |_| None).unwrap()
+    }
+
+    #[test]
+    fn defaults_are_refused() {
+        let problems = check(&Configuration::default());
+
+        assert_eq!(problems.len(), 2, "{problems:?}");
+    }
+
+    #[test]
+    fn public_address_requires_tls_proxy() {
+        let file = "connection_string = \"Server=db;Database=Consum;User=api;Password=x\"\n\
+            jwt_secret = \"0123456789abcdef0123456789abcdef\"\n\
+            addr = \"0.0.0.0:3030\"";
+        assert_eq!(check(&config(file)).len(), 1);
+
+        let file = format!("{file}\ntls_proxy = true");
+        assert!(check(&config(&file)).is_empty());
+    }
+}
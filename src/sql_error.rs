@@ -0,0 +1,109 @@
+use http::StatusCode;
+use http_api_problem::HttpApiProblem;
+use tiberius::error::Error as SqlError;
+
+// Seconds the client should wait before retrying, sent in `Retry-After` header
+const RETRY_BUSY: u64 = 1;
+const RETRY_UNAVAILABLE: u64 = 30;
+
+/// Failures of SQL Server the client can act on, other errors stay internal
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SqlFailure {
+    Duplicate,
+    ConstraintViolation,
+    Busy,
+    Unavailable,
+}
+
+/// Problem for a failed DB call, `None` when the error is not a known SQL Server failure.
+/// The response only tells the kind of failure, the server message is left to the log.
+pub fn classify(e: &anyhow::Error) -> Option<HttpApiProblem> {
+    let failure = e.chain().find_map(|cause| {
+        if let Some(err) = cause.downcast_ref::<SqlError>() {
+            return failure_of(err);
+        }
+        match cause.downcast_ref::<bb8::RunError<SqlError>>()? {
+            bb8::RunError::User(err) => failure_of(err),
+            bb8::RunError::TimedOut => Some(SqlFailure::Unavailable),
+        }
+    })?;
+
+    let problem = match failure {
+        SqlFailure::Duplicate => HttpApiProblem::new(StatusCode::CONFLICT)
+            .type_url("/problems/duplicate-record")
+            .title("Record already exists"),
+        SqlFailure::ConstraintViolation => HttpApiProblem::new(StatusCode::UNPROCESSABLE_ENTITY)
+            .type_url("/problems/constraint-violation")
+            .title("Record refers to a missing record or breaks a constraint"),
+        SqlFailure::Busy => HttpApiProblem::new(StatusCode::SERVICE_UNAVAILABLE)
+            .type_url("/problems/database-busy")
+            .title("Database is busy, retry the request")
+            .value("retryAfter", &RETRY_BUSY),
+        SqlFailure::Unavailable => HttpApiProblem::new(StatusCode::SERVICE_UNAVAILABLE)
+            .type_url("/problems/database-unavailable")
+            .title("Database is not available")
+            .value("retryAfter", &RETRY_UNAVAILABLE),
+    };
+    Some(problem)
+}
+
+fn failure_of(err: &SqlError) -> Option<SqlFailure> {
+    match err {
+        SqlError::Server(token) => failure_of_code(token.code()),
+        SqlError::Io { .. } | SqlError::Tls(_) | SqlError::Routing { .. } => {
+            Some(SqlFailure::Unavailable)
+        }
+        _ => None,
+    }
+}
+
+// https://learn.microsoft.com/en-us/sql/relational-databases/errors-events/database-engine-events-and-errors
+fn failure_of_code(code: u32) -> Option<SqlFailure> {
+    match code {
+        // Unique index, unique constraint or primary key
+        2601 | 2627 => Some(SqlFailure::Duplicate),
+        // Foreign key or check constraint
+        547 => Some(SqlFailure::ConstraintViolation),
+        // Deadlock victim, lock request timeout
+        1205 | 1222 => Some(SqlFailure::Busy),
+        // Login failed, database cannot be opened or is not available
+        18456 | 4060 | 40613 | 40501 => Some(SqlFailure::Unavailable),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tiberius::error::IoErrorKind;
+
+    #[test]
+    fn maps_server_codes() {
+        assert_eq!(failure_of_code(2627), Some(SqlFailure::Duplicate));
+        assert_eq!(failure_of_code(547), Some(SqlFailure::ConstraintViolation));
+        assert_eq!(failure_of_code(1205), Some(SqlFailure::Busy));
+        assert_eq!(failure_of_code(18456), Some(SqlFailure::Unavailable));
+        assert_eq!(failure_of_code(208), None);
+    }
+
+    #[test]
+    fn classifies_pool_and_connection_errors() {
+        let timed_out = anyhow::Error::from(bb8::RunError::<SqlError>::TimedOut);
+        let problem = classify(&timed_out).unwrap();
+        assert_eq!(problem.status, Some(StatusCode::SERVICE_UNAVAILABLE));
+        assert_eq!(
+            problem.get_value::<&str, u64>("retryAfter"),
+            Some(RETRY_UNAVAILABLE)
+        );
+
+        let refused = anyhow::Error::from(SqlError::Io {
+            kind: IoErrorKind::ConnectionRefused,
+            message: "connection refused by db-01:1433".to_string(),
+        })
+        .context("Cannot load orders");
+        let problem = classify(&refused).unwrap();
+        assert!(!problem.json_string().contains("db-01"));
+
+        assert!(classify(&anyhow::anyhow!("Invalid object name 'ConsOrders'")).is_none());
+    }
+}
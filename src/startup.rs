@@ -1,39 +1,55 @@
 use crate::{
-    DBPool, auth, configuration,
+    DBPool,
+    audit::Route,
+    auth::{self, scope},
+    configuration,
     connection_manager::TiberiusConnection,
     db::DB,
+    errors::{InsecureConfiguration, MissingRole, MissingScope},
+    etag::IfMatch,
     handlers,
+    log_file::{RotatingFile, RotationPolicy},
+    metrics,
     model::{ApiKey, User},
+    pagination::PageLinks,
     problem,
+    request_id::{self, RequestIdLayer},
+    revocation, safety, telemetry,
     url_part_utf8_string::UrlPartUtf8String,
 };
-use chrono::DateTime;
-use configuration::Configuration;
+use configuration::{Configuration, LogFormat};
+use futures_util::{Stream, StreamExt};
 use http_api_problem::HttpApiProblem;
-use std::{convert::Infallible, env};
+use serde::de::DeserializeOwned;
+use std::{convert::Infallible, pin::pin, sync::Mutex};
 use tiberius::Config;
 use tokio::{
     runtime::Runtime,
     sync::oneshot::{self, Receiver},
 };
-use warp::Filter;
+use tracing_subscriber::{
+    EnvFilter, Layer, fmt::MakeWriter, layer::SubscriberExt, registry::LookupSpan,
+    util::SubscriberInitExt,
+};
+use warp::{Filter, hyper::body::Buf};
+
+// Largest request body, bigger ones are refused with 413
+const MAX_BODY_SIZE: u64 = 256 * 1024;
 
-pub fn run() {
+pub fn run() -> Result<(), InsecureConfiguration> {
     let (_tx, rx) = oneshot::channel::<()>();
-    run_with_graceful_shutdown(rx);
+    run_with_graceful_shutdown(rx)
 }
 
-pub fn run_with_graceful_shutdown<T>(shutdown_rx: Receiver<T>)
+pub fn run_with_graceful_shutdown<T>(shutdown_rx: Receiver<T>) -> Result<(), InsecureConfiguration>
 where
     T: Send + 'static,
 {
-    if env::var_os("RUST_LOG").is_none() {
-        // Set `RUST_LOG=todos=debug` to see debug logs,
-        // this only shows access logs.
-        unsafe { env::set_var("RUST_LOG", "api=info,service=info") };
-    }
     //pretty_env_logger::init();
-    setup_logger().ok();
+    if let Err(e) = setup_logger() {
+        eprintln!("Cannot set up logging: {e}");
+    }
+    check_safety(configuration::get())?;
 
     // Create the runtime
     let rt = Runtime::new().unwrap();
@@ -43,19 +59,28 @@ where
         let config = configuration::get();
         let manager =
             TiberiusConnection::new(Config::from_ado_string(config.connection_string()).unwrap());
+        // Checking out runs `is_valid`, which rolls back transactions of dropped requests
         let db_pool = bb8::Pool::builder()
             .max_size(config.max_pool())
-            .build_unchecked(manager);
+            .test_on_check_out(true)
+            .build_unchecked(manager.clone());
 
         //test(db_pool.clone()).await;
 
-        let api = api(db_pool).with(warp::log("api")).recover(problem::unpack);
+        tokio::spawn(revocation::refresh(
+            DB::new(db_pool.clone()),
+            config.revocation_refresh(),
+        ));
 
-        info!(target: "service", "Listening on {}", config.addr());
+        // Probes are not logged, load balancers call them every few seconds
+        let api = health_routes(db_pool.clone(), manager)
+            .or(api(db_pool).with(warp::log("api")))
+            .recover(problem::unpack)
+            .map(request_id::with_header)
+            .with(warp::trace(request_id::span))
+            .with(warp::log::custom(metrics::observe_request));
 
-        if generate_auth_token(config).is_none() {
-            return;
-        }
+        info!(target: "service", "Listening on {}", config.addr());
 
         // Use warp 0.4's graceful shutdown API
         warp::serve(api)
@@ -68,6 +93,28 @@ where
             .run()
             .await;
     });
+    telemetry::shutdown();
+
+    Ok(())
+}
+
+// Insecure settings stop the startup, unless running in development mode
+fn check_safety(config: &Configuration) -> Result<(), InsecureConfiguration> {
+    let problems = safety::check(config);
+    if problems.is_empty() {
+        return Ok(());
+    }
+
+    if config.insecure_dev() {
+        for problem in &problems {
+            warn!(target: "service", "Insecure configuration, allowed in development mode: {problem}");
+        }
+        return Ok(());
+    }
+
+    let err = InsecureConfiguration(problems);
+    error!(target: "service", "{err}\nFix the configuration or run with --insecure-dev for local development");
+    Err(err)
 }
 
 // async fn test(pool: DBPool) {
@@ -85,49 +132,155 @@ fn with_db(db_pool: DBPool) -> impl Filter<Extract = (DB,), Error = Infallible>
     warp::any().map(move || DB::new(db_pool.clone()))
 }
 
-// Generate and show token
-fn generate_auth_token(config: &Configuration) -> Option<String> {
-    let exp = DateTime::parse_from_rfc3339("2030-01-01T01:01:01.00Z").unwrap();
-    let token = auth::try_encode_token_exp(config.jwt_secret(), "1", exp.into());
-
-    token.map(|v| {
-        info!(target: "service", "Auth token {v:?}\nSample url: GET http://localhost:3030/orders?api_key={v}");
-        v
-    })
-    .map_err(|error| {
-        error!(target: "service", "Error encoding auth token: {error}");
-        error
-    })
-    .ok()
-
-    // TODO: Not sure which way looks better, this works too
-    // match token {
-    //     Ok(token) => {
-    //         info!(target: "service", "Auth token {:?}", token);
-    //         return Some(token);
-    //     },
-    //     Err(error) => {
-    //         error!(target: "service", "Error encoding auth token: {}", error);
-    //         return None;
-    //     }
-    // }
-}
-
-// API key verification
-fn auth_check() -> impl Filter<Extract = (User,), Error = warp::Rejection> + Copy {
-    warp::query().and_then(|key: ApiKey| async move {
-        let jwt_secret = configuration::get().jwt_secret();
-        let claims = auth::decode_token(jwt_secret, &key.api_key);
-        match claims {
-            Ok(claims) => Ok(User {
-                id: claims.user_id().to_owned(),
-            }),
-            Err(err) => Err(warp::reject::custom(
-                HttpApiProblem::new(http_api_problem::StatusCode::UNAUTHORIZED)
-                    .title(format!("Invalid API key: {:?}", err.kind())),
-            )),
+// Current path and query, to build links to the other pages of a list
+fn with_page_links() -> impl Filter<Extract = (PageLinks,), Error = Infallible> + Clone {
+    warp::path::full()
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .map(|path: warp::path::FullPath, query: String| PageLinks::new(path.as_str(), query))
+}
+
+// `If-Match` header, checked against the current record before it is modified
+fn with_if_match() -> impl Filter<Extract = (IfMatch,), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("if-match").map(IfMatch::new)
+}
+
+// Method and path of the request, recorded in the audit trail
+fn with_route() -> impl Filter<Extract = (Route,), Error = Infallible> + Clone {
+    warp::method().and(warp::path::full()).map(
+        |method: warp::http::Method, path: warp::path::FullPath| {
+            Route(format!("{method} {}", path.as_str()))
+        },
+    )
+}
+
+// API key verification, the key is taken from `Authorization: Bearer`, then `X-Api-Key`,
+// then `?api_key=` if the query string is allowed by configuration.
+// The token must grant the `required` scope, directly or by one of its roles.
+fn auth_check(
+    required: &'static str,
+) -> impl Filter<Extract = (User,), Error = warp::Rejection> + Copy {
+    authorize(required, None)
+}
+
+// Like `auth_check`, for routes which also need the admin role
+fn admin_check(
+    required: &'static str,
+) -> impl Filter<Extract = (User,), Error = warp::Rejection> + Copy {
+    authorize(required, Some(auth::ADMIN_ROLE))
+}
+
+fn authorize(
+    required: &'static str,
+    role: Option<&'static str>,
+) -> impl Filter<Extract = (User,), Error = warp::Rejection> + Copy {
+    warp::header::optional::<String>("authorization")
+        .and(warp::header::optional::<String>("x-api-key"))
+        .and(
+            warp::query::<ApiKey>()
+                .or(warp::any().map(ApiKey::default))
+                .unify(),
+        )
+        .and_then(
+            move |authorization: Option<String>, header_key: Option<String>, query: ApiKey| async move {
+                let config = configuration::get();
+                let key = match (authorization, header_key, query.api_key) {
+                    (Some(authorization), _, _) => auth::bearer_token(&authorization)
+                        .ok_or_else(|| unauthorized("Authorization header must use Bearer scheme"))?
+                        .to_owned(),
+                    (None, Some(key), _) => key,
+                    (None, None, Some(key)) if config.query_api_key() => key,
+                    (None, None, Some(_)) => {
+                        return Err(unauthorized(
+                            "API key in query string is disabled, use Authorization header",
+                        ));
+                    }
+                    (None, None, None) => return Err(unauthorized("Missing API key")),
+                };
+
+                let claims = auth::decode_token(auth::keys(), &key)
+                    .map_err(|err| unauthorized(&format!("Invalid API key: {err}")))?;
+                request_id::record_user(claims.user_id());
+                if claims.jti().is_some_and(revocation::is_revoked) {
+                    return Err(unauthorized("API key has been revoked"));
+                }
+
+                if !claims.granted_scopes().iter().any(|scope| scope == required) {
+                    return Err(warp::reject::custom(problem::from_anyhow(
+                        MissingScope(required).into(),
+                    )));
+                }
+                if let Some(role) = role.filter(|role| !claims.has_role(role)) {
+                    return Err(warp::reject::custom(problem::from_anyhow(
+                        MissingRole(role).into(),
+                    )));
+                }
+                Ok(User {
+                    id: claims.user_id().to_owned(),
+                    enterprise: claims.enterprise_access(),
+                })
+            },
+        )
+}
+
+// JSON request body, errors are rejected as problems with the path of the offending field
+fn json_body<T>() -> impl Filter<Extract = (T,), Error = warp::Rejection> + Clone
+where
+    T: DeserializeOwned + Send,
+{
+    warp::header::optional::<u64>("content-length")
+        .and(warp::header::optional::<String>("content-type"))
+        .and(warp::body::stream())
+        .and_then(
+            |content_length: Option<u64>, content_type: Option<String>, body| async move {
+                if content_type.is_some_and(|content_type| !problem::is_json(&content_type)) {
+                    return Err(warp::reject::custom(problem::unsupported_media_type()));
+                }
+                let body = read_body(content_length, body).await?;
+                let deserializer = &mut serde_json::Deserializer::from_slice(&body);
+                serde_path_to_error::deserialize(deserializer)
+                    .map_err(|err| warp::reject::custom(problem::from_json_error(err)))
+            },
+        )
+}
+
+// Reads the body up to `MAX_BODY_SIZE`, chunked bodies without Content-Length included
+async fn read_body(
+    content_length: Option<u64>,
+    body: impl Stream<Item = Result<impl Buf, warp::Error>>,
+) -> Result<Vec<u8>, warp::Rejection> {
+    let too_large = || warp::reject::custom(problem::payload_too_large());
+    if content_length.is_some_and(|length| length > MAX_BODY_SIZE) {
+        return Err(too_large());
+    }
+
+    let mut body = pin!(body);
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.next().await {
+        let mut chunk = chunk.map_err(|_| warp::reject::custom(problem::unreadable_body()))?;
+        if (bytes.len() + chunk.remaining()) as u64 > MAX_BODY_SIZE {
+            return Err(too_large());
         }
-    })
+        while chunk.has_remaining() {
+            let part = chunk.chunk();
+            bytes.extend_from_slice(part);
+            let read = part.len();
+            chunk.advance(read);
+        }
+    }
+    Ok(bytes)
+}
+
+// Percent-decoded path segment, one which is not UTF-8 is rejected as a problem instead of 404
+async fn url_part(segment: String) -> Result<UrlPartUtf8String, warp::Rejection> {
+    segment
+        .parse()
+        .map_err(|_| warp::reject::custom(problem::invalid_path_segment(&segment)))
+}
+
+fn unauthorized(title: &str) -> warp::Rejection {
+    warp::reject::custom(
+        HttpApiProblem::new(http_api_problem::StatusCode::UNAUTHORIZED).title(title.to_owned()),
+    )
 }
 
 // Endpoints
@@ -136,7 +289,9 @@ pub fn orders(
 ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
     warp::path!("orders")
         .and(warp::get())
-        .and(auth_check())
+        .and(warp::query())
+        .and(with_page_links())
+        .and(auth_check(scope::ORDERS_READ))
         .and(with_db(db))
         .and_then(handlers::list_orders)
 }
@@ -146,8 +301,10 @@ pub fn create_orders_view(
 ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
     warp::path!("orders" / "views")
         .and(warp::post())
-        .and(warp::body::json())
-        .and(auth_check())
+        .and(json_body())
+        .and(warp::query())
+        .and(with_page_links())
+        .and(auth_check(scope::ORDERS_READ))
         .and(with_db(db))
         .and_then(handlers::list_orders_filtered)
 }
@@ -157,7 +314,8 @@ pub fn order(
 ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
     warp::path!("orders" / i32)
         .and(warp::get())
-        .and(auth_check())
+        .and(warp::header::optional::<String>("if-none-match"))
+        .and(auth_check(scope::ORDERS_READ))
         .and(with_db(db))
         .and_then(handlers::get_order)
 }
@@ -167,18 +325,228 @@ pub fn create_order(
 ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
     warp::path!("orders")
         .and(warp::post())
-        .and(warp::body::json())
-        .and(auth_check())
+        .and(json_body())
+        .and(with_route())
+        .and(auth_check(scope::ORDERS_WRITE))
         .and(with_db(db))
         .and_then(handlers::create_order)
 }
 
+pub fn update_order(
+    db: DBPool,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("orders" / i32)
+        .and(warp::put())
+        .and(json_body())
+        .and(with_if_match())
+        .and(with_route())
+        .and(auth_check(scope::ORDERS_WRITE))
+        .and(with_db(db))
+        .and_then(handlers::update_order)
+}
+
+pub fn patch_order(
+    db: DBPool,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("orders" / i32)
+        .and(warp::patch())
+        .and(json_body())
+        .and(with_if_match())
+        .and(with_route())
+        .and(auth_check(scope::ORDERS_WRITE))
+        .and(with_db(db))
+        .and_then(handlers::patch_order)
+}
+
+pub fn delete_order(
+    db: DBPool,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("orders" / i32)
+        .and(warp::delete())
+        .and(warp::query())
+        .and(with_if_match())
+        .and(with_route())
+        .and(auth_check(scope::ORDERS_WRITE))
+        .and(with_db(db))
+        .and_then(handlers::delete_order)
+}
+
+pub fn order_items(
+    db: DBPool,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("orders" / i32 / "items")
+        .and(warp::get())
+        .and(auth_check(scope::ORDERS_READ))
+        .and(with_db(db))
+        .and_then(handlers::list_order_items)
+}
+
+pub fn order_item(
+    db: DBPool,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("orders" / i32 / "items" / i32)
+        .and(warp::get())
+        .and(warp::header::optional::<String>("if-none-match"))
+        .and(auth_check(scope::ORDERS_READ))
+        .and(with_db(db))
+        .and_then(handlers::get_order_item)
+}
+
+pub fn create_order_item(
+    db: DBPool,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("orders" / i32 / "items")
+        .and(warp::post())
+        .and(json_body())
+        .and(with_route())
+        .and(auth_check(scope::ORDERS_WRITE))
+        .and(with_db(db))
+        .and_then(handlers::create_order_item)
+}
+
+pub fn update_order_item(
+    db: DBPool,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("orders" / i32 / "items" / i32)
+        .and(warp::put())
+        .and(json_body())
+        .and(with_if_match())
+        .and(with_route())
+        .and(auth_check(scope::ORDERS_WRITE))
+        .and(with_db(db))
+        .and_then(handlers::update_order_item)
+}
+
+pub fn delete_order_item(
+    db: DBPool,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("orders" / i32 / "items" / i32)
+        .and(warp::delete())
+        .and(with_if_match())
+        .and(with_route())
+        .and(auth_check(scope::ORDERS_WRITE))
+        .and(with_db(db))
+        .and_then(handlers::delete_order_item)
+}
+
+pub fn payments(
+    db: DBPool,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("payments")
+        .and(warp::get())
+        .and(auth_check(scope::PAYMENTS_READ))
+        .and(with_db(db))
+        .and_then(handlers::list_payments)
+}
+
+pub fn order_payments(
+    db: DBPool,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("orders" / i32 / "payments")
+        .and(warp::get())
+        .and(auth_check(scope::PAYMENTS_READ))
+        .and(with_db(db))
+        .and_then(handlers::list_order_payments)
+}
+
+pub fn create_payment(
+    db: DBPool,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("orders" / i32 / "payments")
+        .and(warp::post())
+        .and(json_body())
+        .and(with_route())
+        .and(auth_check(scope::PAYMENTS_WRITE))
+        .and(with_db(db))
+        .and_then(handlers::create_payment)
+}
+
+pub fn payment(
+    db: DBPool,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("payments" / i32)
+        .and(warp::get())
+        .and(warp::header::optional::<String>("if-none-match"))
+        .and(auth_check(scope::PAYMENTS_READ))
+        .and(with_db(db))
+        .and_then(handlers::get_payment)
+}
+
+pub fn delete_payment(
+    db: DBPool,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("payments" / i32)
+        .and(warp::delete())
+        .and(with_if_match())
+        .and(with_route())
+        .and(auth_check(scope::PAYMENTS_WRITE))
+        .and(with_db(db))
+        .and_then(handlers::delete_payment)
+}
+
+pub fn requests(
+    db: DBPool,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("requests")
+        .and(warp::get())
+        .and(warp::query())
+        .and(auth_check(scope::REQUESTS_READ))
+        .and(with_db(db))
+        .and_then(handlers::list_requests)
+}
+
+pub fn request(
+    db: DBPool,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("requests" / i32)
+        .and(warp::get())
+        .and(auth_check(scope::REQUESTS_READ))
+        .and(with_db(db))
+        .and_then(handlers::get_request)
+}
+
+pub fn create_request(
+    db: DBPool,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("requests")
+        .and(warp::post())
+        .and(json_body())
+        .and(with_route())
+        .and(auth_check(scope::REQUESTS_WRITE))
+        .and(with_db(db))
+        .and_then(handlers::create_request)
+}
+
+pub fn cancel_request(
+    db: DBPool,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("requests" / i32 / "cancel")
+        .and(warp::post())
+        .and(with_route())
+        .and(auth_check(scope::REQUESTS_WRITE))
+        .and(with_db(db))
+        .and_then(handlers::cancel_request)
+}
+
+pub fn refuse_request(
+    db: DBPool,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("requests" / i32 / "refuse")
+        .and(warp::post())
+        .and(with_route())
+        .and(auth_check(scope::REQUESTS_WRITE))
+        .and(with_db(db))
+        .and_then(handlers::refuse_request)
+}
+
 pub fn categories(
     db: DBPool,
 ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
     warp::path!("categories")
         .and(warp::get())
-        .and(auth_check())
+        .and(warp::query())
+        .and(with_page_links())
+        .and(auth_check(scope::CATEGORIES_READ))
         .and(with_db(db))
         .and_then(handlers::list_categories)
 }
@@ -188,38 +556,100 @@ pub fn category(
 ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
     warp::path!("categories" / i32)
         .and(warp::get())
-        .and(auth_check())
+        .and(warp::header::optional::<String>("if-none-match"))
+        .and(auth_check(scope::CATEGORIES_READ))
         .and(with_db(db))
         .and_then(handlers::get_category)
 }
 
+pub fn category_tree(
+    db: DBPool,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("categories" / "tree")
+        .and(warp::get())
+        .and(auth_check(scope::CATEGORIES_READ))
+        .and(with_db(db))
+        .and_then(handlers::get_category_tree)
+}
+
+pub fn category_descendants(
+    db: DBPool,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("categories" / i32 / "descendants")
+        .and(warp::get())
+        .and(auth_check(scope::CATEGORIES_READ))
+        .and(with_db(db))
+        .and_then(handlers::get_category_descendants)
+}
+
+pub fn category_ancestors(
+    db: DBPool,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("categories" / i32 / "ancestors")
+        .and(warp::get())
+        .and(auth_check(scope::CATEGORIES_READ))
+        .and(with_db(db))
+        .and_then(handlers::get_category_ancestors)
+}
+
 pub fn create_category(
     db: DBPool,
 ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
     warp::path!("categories")
         .and(warp::post())
-        .and(warp::body::json())
-        .and(auth_check())
+        .and(json_body())
+        .and(with_route())
+        .and(auth_check(scope::CATEGORIES_WRITE))
         .and(with_db(db))
         .and_then(handlers::create_category)
 }
 
+pub fn update_category(
+    db: DBPool,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("categories" / i32)
+        .and(warp::patch())
+        .and(json_body())
+        .and(with_if_match())
+        .and(with_route())
+        .and(auth_check(scope::CATEGORIES_WRITE))
+        .and(with_db(db))
+        .and_then(handlers::update_category)
+}
+
 pub fn delete_category(
     db: DBPool,
 ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
     warp::path!("categories" / i32)
         .and(warp::delete())
-        .and(auth_check())
+        .and(warp::query())
+        .and(with_if_match())
+        .and(with_route())
+        .and(auth_check(scope::CATEGORIES_WRITE))
         .and(with_db(db))
         .and_then(handlers::delete_category)
 }
 
+pub fn suppliers(
+    db: DBPool,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("suppliers")
+        .and(warp::get())
+        .and(warp::query())
+        .and(warp::query())
+        .and(with_page_links())
+        .and(auth_check(scope::SUPPLIERS_READ))
+        .and(with_db(db))
+        .and_then(handlers::list_suppliers)
+}
+
 pub fn supplier_by_id(
     db: DBPool,
 ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
     warp::path!("suppliers" / i32)
         .and(warp::get())
-        .and(auth_check())
+        .and(warp::header::optional::<String>("if-none-match"))
+        .and(auth_check(scope::SUPPLIERS_READ))
         .and(with_db(db))
         .and_then(handlers::get_supplier_by_id)
 }
@@ -227,9 +657,10 @@ pub fn supplier_by_id(
 pub fn supplier_by_name(
     db: DBPool,
 ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
-    warp::path!("suppliers" / "name" / UrlPartUtf8String)
+    warp::path!("suppliers" / "name" / String)
+        .and_then(url_part)
         .and(warp::get())
-        .and(auth_check())
+        .and(auth_check(scope::SUPPLIERS_READ))
         .and(with_db(db))
         .and_then(handlers::get_supplier_by_name)
 }
@@ -239,53 +670,370 @@ pub fn create_supplier(
 ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
     warp::path!("suppliers")
         .and(warp::post())
-        .and(warp::body::json())
-        .and(auth_check())
+        .and(json_body())
+        .and(with_route())
+        .and(auth_check(scope::SUPPLIERS_WRITE))
         .and(with_db(db))
         .and_then(handlers::create_supplier)
 }
 
-// Aggregate all endpoints
+pub fn update_supplier(
+    db: DBPool,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("suppliers" / i32)
+        .and(warp::put())
+        .and(json_body())
+        .and(with_if_match())
+        .and(with_route())
+        .and(auth_check(scope::SUPPLIERS_WRITE))
+        .and(with_db(db))
+        .and_then(handlers::update_supplier)
+}
 
-pub fn api(
+pub fn patch_supplier(
+    db: DBPool,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("suppliers" / i32)
+        .and(warp::patch())
+        .and(json_body())
+        .and(with_if_match())
+        .and(with_route())
+        .and(auth_check(scope::SUPPLIERS_WRITE))
+        .and(with_db(db))
+        .and_then(handlers::patch_supplier)
+}
+
+pub fn delete_supplier(
+    db: DBPool,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("suppliers" / i32)
+        .and(warp::delete())
+        .and(with_if_match())
+        .and(with_route())
+        .and(auth_check(scope::SUPPLIERS_WRITE))
+        .and(with_db(db))
+        .and_then(handlers::delete_supplier)
+}
+
+pub fn issue_token(
+    db: DBPool,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("auth" / "tokens")
+        .and(warp::post())
+        .and(json_body())
+        .and(with_route())
+        .and(admin_check(scope::TOKENS_WRITE))
+        .and(with_db(db))
+        .and_then(handlers::issue_token)
+}
+
+pub fn revoke_token(
+    db: DBPool,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("auth" / "tokens" / String)
+        .and(warp::delete())
+        .and(with_route())
+        .and(admin_check(scope::TOKENS_WRITE))
+        .and(with_db(db))
+        .and_then(handlers::revoke_token)
+}
+
+pub fn audit(
+    db: DBPool,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("audit")
+        .and(warp::get())
+        .and(warp::query())
+        .and(warp::query())
+        .and(with_page_links())
+        .and(auth_check(scope::AUDIT_READ))
+        .and(with_db(db))
+        .and_then(handlers::list_audit)
+}
+
+// Health and version endpoints, without authentication
+
+pub fn live() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("health" / "live")
+        .and(warp::get())
+        .and_then(handlers::live)
+}
+
+pub fn ready(
+    db: DBPool,
+    manager: TiberiusConnection,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("health" / "ready")
+        .and(warp::get())
+        .and(warp::any().map(move || db.clone()))
+        .and(warp::any().map(move || manager.clone()))
+        .and_then(handlers::ready)
+}
+
+pub fn version() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("version")
+        .and(warp::get())
+        .and_then(handlers::version)
+}
+
+pub fn metrics(
+    db: DBPool,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("metrics")
+        .and(warp::get())
+        .and(auth_check(scope::METRICS_READ))
+        .and(with_db(db))
+        .and_then(handlers::metrics)
+}
+
+fn health_routes(
+    db: DBPool,
+    manager: TiberiusConnection,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    live().or(ready(db, manager)).or(version())
+}
+
+// Aggregate all endpoints, grouped by resource to keep the filter types shallow
+
+fn order_routes(
     db: DBPool,
 ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
     orders(db.clone())
         .or(create_orders_view(db.clone()))
         .or(order(db.clone()))
         .or(create_order(db.clone()))
-        .or(categories(db.clone()))
+        .or(update_order(db.clone()))
+        .or(patch_order(db.clone()))
+        .or(delete_order(db))
+}
+
+fn order_item_routes(
+    db: DBPool,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    order_items(db.clone())
+        .or(order_item(db.clone()))
+        .or(create_order_item(db.clone()))
+        .or(update_order_item(db.clone()))
+        .or(delete_order_item(db))
+}
+
+fn payment_routes(
+    db: DBPool,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    payments(db.clone())
+        .or(order_payments(db.clone()))
+        .or(create_payment(db.clone()))
+        .or(payment(db.clone()))
+        .or(delete_payment(db))
+}
+
+fn request_routes(
+    db: DBPool,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    requests(db.clone())
+        .or(request(db.clone()))
+        .or(create_request(db.clone()))
+        .or(cancel_request(db.clone()))
+        .or(refuse_request(db))
+}
+
+fn category_routes(
+    db: DBPool,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    categories(db.clone())
         .or(category(db.clone()))
+        .or(category_tree(db.clone()))
+        .or(category_descendants(db.clone()))
+        .or(category_ancestors(db.clone()))
         .or(create_category(db.clone()))
-        .or(delete_category(db.clone()))
+        .or(update_category(db.clone()))
+        .or(delete_category(db))
+}
+
+fn supplier_routes(
+    db: DBPool,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    suppliers(db.clone())
         .or(supplier_by_id(db.clone()))
         .or(supplier_by_name(db.clone()))
-        .or(create_supplier(db))
-}
-
-fn setup_logger() -> Result<(), fern::InitError> {
-    let mut logger = fern::Dispatch::new()
-        .format(|out, message, record| {
-            out.finish(format_args!(
-                "{}[{}][{}] {}",
-                chrono::Local::now().format("[%Y-%m-%d][%H:%M:%S]"),
-                record.target(),
-                record.level(),
-                message
-            ));
-        })
-        .level(log::LevelFilter::Debug);
-
-    if configuration::get().stdout_enabled() {
-        logger = logger.chain(std::io::stdout());
+        .or(create_supplier(db.clone()))
+        .or(update_supplier(db.clone()))
+        .or(patch_supplier(db.clone()))
+        .or(delete_supplier(db))
+}
+
+pub fn api(
+    db: DBPool,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    order_routes(db.clone())
+        .or(order_item_routes(db.clone()))
+        .or(payment_routes(db.clone()))
+        .or(request_routes(db.clone()))
+        .or(category_routes(db.clone()))
+        .or(supplier_routes(db.clone()))
+        .or(issue_token(db.clone()))
+        .or(revoke_token(db.clone()))
+        .or(audit(db.clone()))
+        .or(metrics(db))
+}
+
+// Log lines of the `log` crate are forwarded to `tracing`, so they carry the request id as well
+fn setup_logger() -> anyhow::Result<()> {
+    let config = configuration::get();
+    let filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(config.log_filter()));
+
+    let stdout = config
+        .stdout_enabled()
+        .then(|| fmt_layer(config.log_format(), std::io::stdout, true));
+
+    let file = match config.log_path() {
+        Some(path) => {
+            println!("Logging to file {path}");
+            let policy = RotationPolicy {
+                period: config.log_rotation(),
+                max_size: config.log_max_size(),
+                max_files: config.log_max_files(),
+                compress: config.log_compress(),
+            };
+            let file = RotatingFile::open(path, policy)?;
+            Some(fmt_layer(config.log_format(), Mutex::new(file), false))
+        }
+        None => None,
+    };
+
+    // Logging goes on without span export when the exporter cannot be built
+    let telemetry = telemetry::layer(config).unwrap_or_else(|e| {
+        eprintln!("Cannot export spans: {e}");
+        None
+    });
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(RequestIdLayer)
+        .with(telemetry)
+        .with(stdout)
+        .with(file)
+        .try_init()?;
+
+    Ok(())
+}
+
+fn fmt_layer<S, W>(format: LogFormat, writer: W, ansi: bool) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer().with_writer(writer);
+    match format {
+        LogFormat::Text => layer.with_ansi(ansi).boxed(),
+        LogFormat::Json => layer
+            .json()
+            .with_current_span(false)
+            .with_span_list(true)
+            .boxed(),
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::CreateToken;
+    use auth::Keys;
+    use warp::{http::StatusCode, hyper::body::Bytes};
 
-    if let Some(path) = configuration::get().log_path() {
-        println!("Logging to file {path}");
-        logger = logger.chain(fern::log_file(path)?);
+    fn bearer(scopes: &[&str], roles: &[&str]) -> String {
+        let config = configuration::init(Configuration::default());
+        let keys = auth::init_keys(Keys::load(config).unwrap());
+        let request = CreateToken {
+            sub: "1".to_string(),
+            ttl: "1h".to_string(),
+            scopes: scopes.iter().map(|s| s.to_string()).collect(),
+            roles: roles.iter().map(|r| r.to_string()).collect(),
+            enterprise: None,
+        };
+        format!(
+            "Bearer {}",
+            auth::issue_token(keys, &request).unwrap().token
+        )
     }
 
-    logger.apply()?;
+    // Never connects, requests fail with 503 once they need the database
+    fn unconnected_pool() -> DBPool {
+        let manager = TiberiusConnection::new(Config::new());
+        bb8::Pool::builder()
+            .connection_timeout(std::time::Duration::from_millis(100))
+            .build_unchecked(manager)
+    }
 
-    Ok(())
+    fn problem_status(rejection: warp::Rejection) -> Option<StatusCode> {
+        rejection.find::<HttpApiProblem>()?.status
+    }
+
+    #[tokio::test]
+    async fn body_size_is_limited_without_content_length() {
+        let chunk = || Ok::<_, warp::Error>(Bytes::from(vec![b' '; 100 * 1024]));
+
+        let body = read_body(None, futures_util::stream::iter([chunk(), chunk()]))
+            .await
+            .unwrap();
+        assert_eq!(body.len(), 200 * 1024);
+
+        let rejection = read_body(
+            None,
+            futures_util::stream::iter([chunk(), chunk(), chunk()]),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(
+            problem_status(rejection),
+            Some(StatusCode::PAYLOAD_TOO_LARGE)
+        );
+
+        let rejection = read_body(
+            Some(MAX_BODY_SIZE + 1),
+            futures_util::stream::iter([chunk()]),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(
+            problem_status(rejection),
+            Some(StatusCode::PAYLOAD_TOO_LARGE)
+        );
+    }
+
+    #[tokio::test]
+    async fn only_admins_issue_tokens() {
+        let clerk = bearer(&[scope::TOKENS_WRITE], &[]);
+        let admin = bearer(&[], &[auth::ADMIN_ROLE]);
+        let body = r#"{"sub": "2", "ttl": "1h", "roles": ["admin"]}"#;
+
+        // The admin gets no token either, as its issue cannot be audited
+        for (token, status) in [
+            (clerk, StatusCode::FORBIDDEN),
+            (admin, StatusCode::SERVICE_UNAVAILABLE),
+        ] {
+            let response = warp::test::request()
+                .method("POST")
+                .path("/auth/tokens")
+                .header("authorization", token)
+                .header("content-type", "application/json")
+                .body(body)
+                .reply(&issue_token(unconnected_pool()).recover(problem::unpack))
+                .await;
+            assert_eq!(response.status(), status);
+            assert!(!String::from_utf8_lossy(response.body()).contains("\"token\""));
+        }
+    }
+
+    #[tokio::test]
+    async fn only_admins_revoke_tokens() {
+        let response = warp::test::request()
+            .method("DELETE")
+            .path("/auth/tokens/0d9b3c54-ef3e-4c5a-9d0e-8f25b0a6f3a1")
+            .header("authorization", bearer(&[scope::TOKENS_WRITE], &[]))
+            .reply(&revoke_token(unconnected_pool()).recover(problem::unpack))
+            .await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
 }
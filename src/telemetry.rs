@@ -0,0 +1,52 @@
+use std::sync::OnceLock;
+
+use opentelemetry::{KeyValue, trace::TracerProvider as _};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{Resource, trace::SdkTracerProvider};
+use tracing_subscriber::{Layer, registry::LookupSpan};
+
+use crate::configuration::{Configuration, OtelExporter};
+
+static PROVIDER: OnceLock<SdkTracerProvider> = OnceLock::new();
+
+/// Layer exporting spans to the configured exporter, `None` when export is off.
+/// Must be built outside of the tokio runtime, the OTLP exporter uses a blocking HTTP client.
+pub fn layer<S>(config: &Configuration) -> anyhow::Result<Option<Box<dyn Layer<S> + Send + Sync>>>
+where
+    S: tracing::Subscriber + for<'a> LookupSpan<'a> + Send + Sync,
+{
+    let resource = Resource::builder()
+        .with_service_name(env!("CARGO_PKG_NAME"))
+        .with_attribute(KeyValue::new("service.version", env!("CARGO_PKG_VERSION")))
+        .build();
+    let builder = SdkTracerProvider::builder().with_resource(resource);
+
+    let provider = match config.otel_exporter() {
+        OtelExporter::None => return Ok(None),
+        OtelExporter::Otlp => {
+            let mut exporter = opentelemetry_otlp::SpanExporter::builder().with_http();
+            if let Some(endpoint) = config.otel_endpoint() {
+                exporter = exporter.with_endpoint(endpoint);
+            }
+            builder.with_batch_exporter(exporter.build()?).build()
+        }
+        OtelExporter::Stdout => builder
+            .with_simple_exporter(opentelemetry_stdout::SpanExporter::default())
+            .build(),
+    };
+
+    let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+    PROVIDER.get_or_init(|| provider);
+    Ok(Some(
+        tracing_opentelemetry::layer().with_tracer(tracer).boxed(),
+    ))
+}
+
+/// Exports the spans still buffered, called when the service stops
+pub fn shutdown() {
+    if let Some(provider) = PROVIDER.get()
+        && let Err(e) = provider.shutdown()
+    {
+        eprintln!("Cannot export remaining spans: {e}");
+    }
+}
@@ -72,14 +72,18 @@ pub fn run_service() -> Result<()> {
         process_id: None,
     })?;
 
-    startup::run_with_graceful_shutdown(shutdown_rx);
+    // Refused startup is already reported in the log
+    let exit_code = match startup::run_with_graceful_shutdown(shutdown_rx) {
+        Ok(()) => ServiceExitCode::Win32(0),
+        Err(_) => ServiceExitCode::ServiceSpecific(1),
+    };
 
     // Tell the system that service has stopped.
     status_handle.set_service_status(ServiceStatus {
         service_type: SERVICE_TYPE,
         current_state: ServiceState::Stopped,
         controls_accepted: ServiceControlAccept::empty(),
-        exit_code: ServiceExitCode::Win32(0),
+        exit_code,
         checkpoint: 0,
         wait_hint: Duration::default(),
         process_id: None,